@@ -13,96 +13,873 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fmt;
+use std::io::{self, Write};
+use std::ops::Range;
 
 type Tokens = Vec<Token>;
 
+/// The deepest nesting of calls before execution is aborted.
+const MAX_CALL_DEPTH: usize = 1 << 16;
+
+/// The index of `base`, the number base used to parse and print numbers,
+/// among the cells reserved at the bottom of the data space for system
+/// variables.
+const BASE: usize = 0;
+
+/// The index of `>in`, the offset of the parse position within the input
+/// buffer.
+const TO_IN: usize = 1;
+
+/// The index of `state`, which is true while a definition is being compiled.
+const STATE: usize = 2;
+
+/// The identifier of the only word list, which holds every definition.
+const FORTH_WORDLIST: Cell = 1;
+
+/// The number of cells reserved for system variables.
+const SYSTEM_CELLS: usize = 3;
+
+/// The size in bytes of the buffer for pictured numeric output, which follows
+/// the system variables.
+const PICTURE_SIZE: usize = 256;
+
+/// The size in bytes of the input buffer, which follows the data space, that
+/// is kept addressable when the data space is limited to what a cell can
+/// address.
+const INPUT_SIZE: usize = 1024;
+
+/// The digits of every supported number base.
+const DIGITS: &[u8; 36] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ";
+
+/// A single cell, held at the widest supported width and kept sign-extended
+/// from the configured one.
+type Cell = i64;
+
+/// A double-cell number, likewise sign-extended from twice the cell width.
+type Double = i128;
+
+/// Options controlling the behavior of a `Machine`.
+#[derive(Clone)]
+pub struct Config {
+    /// Within a definition, resolve the word's own name to the definition
+    /// itself (like `recurse`) rather than to its previous definition.
+    pub recursive_names: bool,
+    /// The size of the data space in bytes, not counting the space reserved
+    /// for system variables such as `base` and for pictured numeric output.
+    /// It is limited so that a cell can address it, and the input buffer
+    /// which follows it.
+    pub memory_size: usize,
+    /// How arithmetic results that do not fit in a cell are handled.
+    pub arithmetic: Arithmetic,
+    /// The rounding followed by `/`, `mod`, `/mod`, `*/` and `*/mod`.
+    pub division: Division,
+    /// The number of bits in a cell.
+    pub cell_width: CellWidth,
+}
+
+/// The treatment of arithmetic overflow.
+#[derive(Clone, Copy, Default)]
+pub enum Arithmetic {
+    /// Wrap around, as is conventional in Forth.
+    #[default]
+    Wrapping,
+    /// Abort with an error.
+    Checked,
+}
+
+/// The rounding of integer division.
+#[derive(Clone, Copy, Default)]
+pub enum Division {
+    /// Round the quotient toward zero, so the remainder takes the sign of
+    /// the dividend.
+    #[default]
+    Symmetric,
+    /// Round the quotient toward negative infinity, so the remainder takes
+    /// the sign of the divisor.
+    Floored,
+}
+
+/// The supported cell widths.
+#[derive(Clone, Copy, Default)]
+pub enum CellWidth {
+    Bits16,
+    #[default]
+    Bits32,
+    Bits64,
+}
+
+impl CellWidth {
+    fn bits(self) -> u32 {
+        match self {
+            Self::Bits16 => 16,
+            Self::Bits32 => 32,
+            Self::Bits64 => 64,
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            recursive_names: false,
+            memory_size: 1 << 16,
+            arithmetic: Arithmetic::default(),
+            division: Division::default(),
+            cell_width: CellWidth::default(),
+        }
+    }
+}
+
+/// Where programs read input from, such as a terminal or a canned script,
+/// as supplied by the host.
+pub trait InputSource {
+    /// Read the next line of input without its line terminator, or `None` at
+    /// the end of the input.
+    fn read_line(&mut self) -> Option<String>;
+
+    /// Whether a line can be read without waiting for one.
+    fn ready(&mut self) -> bool {
+        false
+    }
+}
+
+/// Any sequence of lines is a source of input, such as the lines of a reader
+/// or a script held in memory.
+impl<I: Iterator<Item = String>> InputSource for I {
+    fn read_line(&mut self) -> Option<String> {
+        self.next()
+    }
+
+    /// A line is ready if the iterator promises at least one more, as one
+    /// over lines held in memory does, unlike one that waits on a terminal.
+    fn ready(&mut self) -> bool {
+        self.size_hint().0 > 0
+    }
+}
+
 pub struct Machine {
-    dictionary: HashMap<String, Vec<Tokens>>,
-    stack: Vec<i32>,
+    config: Config,
+    /// The history of definitions of each word, as indices into `words`.
+    dictionary: HashMap<String, Vec<usize>>,
+    /// Every compiled definition, indexed by its execution token.
+    words: Vec<Definition>,
+    stack: Vec<Cell>,
+    return_stack: Vec<Cell>,
+    #[cfg(feature = "float")]
+    float_stack: Vec<f64>,
+    /// The data space, addressed by byte.
+    memory: Vec<u8>,
+    /// The address of the next free byte of data space.
+    here: usize,
+    /// The address of the most recent transient string, which are built
+    /// from the end of the data space toward `here` without reserving it.
+    transient: usize,
+    /// The execution token of the most recent definition, for `immediate`.
+    latest: usize,
+    /// The most recent word defined by `create`, for `does>`.
+    created: Option<usize>,
+    /// The address of the start of the pictured numeric output, which is
+    /// built from the end of its buffer toward lower addresses.
+    hold: usize,
+    /// The text of each substitution defined by `replaces`, by its name in
+    /// lowercase.
+    substitutions: HashMap<Vec<u8>, Vec<u8>>,
+    /// The definition being compiled, from its `:` until its `;`, which may
+    /// span several phrases.
+    definition: Option<Compiler>,
+    /// The phrase being interpreted, compiled until the end of any control
+    /// structure and then executed.
+    phrase: Compiler,
+    /// Whether a `(` comment in the definition being compiled has yet to be
+    /// closed by `)` on a later line.
+    commenting: bool,
+    /// Where `key`, `accept` and `refill` read input from.
+    source: Box<dyn InputSource>,
+    /// The rest of the line most recently read by `key`, including its line
+    /// terminator.
+    pending: VecDeque<char>,
 }
 
 impl Default for Machine {
     fn default() -> Self {
+        Self::new(Config::default())
+    }
+}
+
+impl Machine {
+    pub fn new(config: Config) -> Self {
         macro_rules! def {
             ($name:literal, $( $word:tt ),+) => {
                 ($name.to_string(), vec![$( def!(@, $word) ),+])
             };
+            (@, [$kind:ident]) => {
+                Token::Define(Defining::$kind)
+            };
+            (@, {$directive:ident}) => {
+                Token::Directive(Directive::$directive)
+            };
             (@, $val:literal) => {
-                Token::Number($val as i32)
+                Token::Number($val as Cell)
+            };
+            (@, ($val:expr)) => {
+                Token::Number($val as Cell)
             };
             (@, $val:ident) => {
                 Token::Builtin(Word::$val)
             };
         }
 
-        Self::with_dictionary(HashMap::from([
+        let cell = config.cell_width.bits() as usize / 8;
+        let dictionary = HashMap::from([
+            def!("!", Store),                             // ( n addr -- )
+            def!("#", NumberSign),                        // ( ud1 -- ud2 )
+            def!("#>", NumberSignGreater),                // ( xd -- c-addr u )
+            def!("#s", NumberSignS),                      // ( ud1 -- ud2 )
+            def!("'", Tick),                              // ( "<spaces>name" -- xt )
+            def!("(", { Paren }),                         // ( "ccc<paren>" -- )
+            def!(")", { CloseParen }),                    // ( -- )
             def!("*", Star),                              // ( n1 n2 -- prod )
+            def!("*/", StarSlash),                        // ( n1 n2 n3 -- n4 )
+            def!("*/mod", StarSlashMod),                  // ( n1 n2 n3 -- rem quot )
             def!("+", Plus),                              // ( n1 n2 -- sum )
+            def!("+!", PlusStore),                        // ( n addr -- )
+            def!("+loop", { PlusLoop }),                  // ( C: do-sys -- ) ( n -- )
+            def!(",", Comma),                             // ( n -- )
             def!("-", Minus),                             // ( n1 n2 -- diff )
+            def!("-trailing", DashTrailing),              // ( c-addr u1 -- c-addr u2 )
             def!(".", Dot),                               // ( n -- )
+            def!(".\"", { DotQuote }),                    // ( "ccc<quote>" -- )
+            def!(".(", { DotParen }),                     // ( "ccc<paren>" -- )
+            def!(".RS", ReturnStackPrint),                // ( -- )
             def!(".S", StackPrint),                       // ( -- )
+            def!(".r", DotR),                             // ( n1 n2 -- )
             def!("/", Slash),                             // ( n1 n2 -- quot )
             def!("/mod", SlashMod),                       // ( n1 n2 -- quot rem )
+            def!("/string", SlashString),                 // ( c-addr1 u1 n -- c-addr2 u2 )
+            def!("0<", 0, Less),                          // ( n -- flag )
+            def!("0<>", 0, Equals, Invert),               // ( n -- flag )
+            def!("0=", 0, Equals),                        // ( n -- flag )
+            def!("0>", 0, Greater),                       // ( n -- flag )
+            def!("1+", 1, Plus),                          // ( n1 -- n2 )
+            def!("1-", 1, Minus),                         // ( n1 -- n2 )
+            def!("2*", TwoStar),                          // ( n1 -- n2 )
+            def!("2/", TwoSlash),                         // ( n1 -- n2 )
             def!("2drop", Drop, Drop),                    // ( d -- )
             def!("2dup", Swap, Dup, Rot, Dup, Rot, Swap), // ( d -- d d )
+            def!("2!", TwoStore),                         // ( d addr -- )
+            def!("2>r", TwoToR),                          // ( d -- ) ( R: -- d )
             def!("2over", TwoOver),                       // ( d1 d2 -- d1 d2 d1 )
+            def!("2r>", TwoRFrom),                        // ( -- d ) ( R: d -- )
+            def!("2r@", TwoRFetch),                       // ( -- d ) ( R: d -- d )
+            def!("2@", TwoFetch),                         // ( addr -- d )
+            def!("2constant", [TwoConstant]),             // ( x1 x2 "<spaces>name" -- )
             def!("2swap", TwoSwap),                       // ( d1 d2 -- d2 d1 )
+            def!("2variable", [TwoVariable]),             // ( "<spaces>name" -- )
+            def!(":", Colon),                             // ( "<spaces>name" -- )
+            def!(";", { Semicolon }),                     // ( -- )
+            def!("<", Less),                              // ( n1 n2 -- flag )
+            def!("<#", LessNumberSign),                   // ( -- )
+            def!("<=", Greater, Invert),                  // ( n1 n2 -- flag )
+            def!("<>", Equals, Invert),                   // ( n1 n2 -- flag )
+            def!("=", Equals),                            // ( n1 n2 -- flag )
+            def!(">", Greater),                           // ( n1 n2 -- flag )
+            def!(">=", Less, Invert),                     // ( n1 n2 -- flag )
+            def!(">body", ToBody),                        // ( xt -- a-addr )
+            def!(">in", (TO_IN * cell)),                  // ( -- addr )
+            def!(">r", ToR),                              // ( n -- ) ( R: -- n )
+            def!("?do", { QuestionDo }),                  // ( C: -- do-sys ) ( n1 n2 -- )
+            def!("@", Fetch),                             // ( addr -- n )
+            def!("[", { LeftBracket }),                   // ( -- )
+            def!("[']", { BracketTick }),                 // ( "<spaces>name" -- ) ( -- xt )
+            def!("[char]", { BracketChar }),              // ( "<spaces>name" -- ) ( -- char )
+            def!("]", RightBracket),                      // ( -- )
+            def!("abs", Abs),                             // ( n -- u )
+            def!("accept", Accept),                       // ( c-addr +n1 -- +n2 )
+            def!("align", Align),                         // ( -- )
+            def!("aligned", Aligned),                     // ( addr -- a-addr )
+            def!("again", { Again }),                     // ( C: dest -- )
+            def!("allot", Allot),                         // ( n -- )
+            def!("and", And),                             // ( n1 n2 -- n3 )
+            def!("base", (BASE * cell)),                  // ( -- addr )
+            def!("begin", { Begin }),                     // ( C: -- dest )
+            def!("binary", 2, (BASE * cell), Store),      // ( -- )
+            def!("blank", ' ', Fill),                     // ( c-addr u -- )
+            def!("c\"", { CQuote }),                      // ( "ccc<quote>" -- ) ( -- c-addr )
+            def!("c!", CStore),                           // ( char addr -- )
+            def!("c,", CComma),                           // ( char -- )
+            def!("c@", CFetch),                           // ( addr -- char )
+            def!("cell+", (cell), Plus),                  // ( addr1 -- addr2 )
+            def!("cells", (cell), Star),                  // ( n1 -- n2 )
+            def!("chars", 1, Star),                       // ( n1 -- n2 )
+            def!("cmove", CMove),                         // ( c-addr1 c-addr2 u -- )
+            def!("cmove>", CMoveUp),                      // ( c-addr1 c-addr2 u -- )
+            def!("compare", Compare),                     // ( c-addr1 u1 c-addr2 u2 -- n )
+            def!("compile,", CompileComma),               // ( xt -- )
+            def!("constant", [Constant]),                 // ( x "<spaces>name" -- )
+            def!("count", Count),                         // ( c-addr1 -- c-addr2 u )
+            def!("create", [Create]),                     // ( "<spaces>name" -- )
             def!("cr", '\r', Emit, '\n', Emit),           // ( -- )
+            def!("d+", DPlus),                            // ( d1 d2 -- d3 )
+            def!("d-", DMinus),                           // ( d1 d2 -- d3 )
+            def!("d.", DDot),                             // ( d -- )
+            def!("d.r", DDotR),                           // ( d n -- )
+            def!("d0=", Or, 0, Equals),                   // ( d -- flag )
+            def!("d2*", DTwoStar),                        // ( d1 -- d2 )
+            def!("d2/", DTwoSlash),                       // ( d1 -- d2 )
+            def!("d<", DLess),                            // ( d1 d2 -- flag )
+            def!("d=", DEquals),                          // ( d1 d2 -- flag )
+            def!("d>s", DToS),                            // ( d -- n )
+            def!("dabs", DAbs),                           // ( d -- ud )
+            def!("decimal", 10, (BASE * cell), Store),    // ( -- )
+            def!("do", { Do }),                           // ( C: -- do-sys ) ( n1 n2 -- )
+            def!("does>", { Does }),                      // ( C: colon-sys1 -- colon-sys2 )
+            def!("dnegate", DNegate),                     // ( d1 -- d2 )
             def!("drop", Drop),                           // ( n -- )
             def!("dup", Dup),                             // ( n -- n n )
+            def!("else", { Else }),                       // ( C: orig1 -- orig2 )
             def!("emit", Emit),                           // ( -- )
+            def!("execute", Execute),                     // ( i*x xt -- j*x )
+            def!("exit", { Exit }),                       // ( -- )
+            def!("false", 0),                             // ( -- flag )
+            def!("fill", Fill),                           // ( c-addr u char -- )
+            def!("find", Find),                           // ( c-addr -- c-addr 0 | xt 1 | xt -1 )
+            def!("fm/mod", FmMod),                        // ( d n -- rem quot )
+            def!("forget", Forget),                       // ( "<spaces>name" -- )
+            def!("forth-wordlist", (FORTH_WORDLIST)),     // ( -- wid )
+            def!("here", Here),                           // ( -- addr )
+            def!("hex", 16, (BASE * cell), Store),        // ( -- )
+            def!("hold", Hold),                           // ( char -- )
+            def!("i", I),                                 // ( -- n ) ( R: loop -- loop )
+            def!("if", { If }),                           // ( C: -- orig ) ( x -- )
+            def!("immediate", Immediate),                 // ( -- )
+            def!("invert", Invert),                       // ( n1 -- n2 )
+            def!("j", J),                                 // ( -- n ) ( R: l1 l2 -- l1 l2 )
+            def!("key", Key),                             // ( -- char )
+            def!("key?", KeyQuestion),                    // ( -- flag )
+            def!("lshift", LShift),                       // ( n1 u -- n2 )
+            def!("leave", { Leave }),                     // ( -- ) ( R: loop -- )
+            def!("literal", { Literal }),                 // ( x -- ) ( -- x )
+            def!("loop", { Loop }),                       // ( C: do-sys -- )
+            def!("m*", MStar),                            // ( n1 n2 -- d )
+            def!("m*/", MStarSlash),                      // ( d1 n1 n2 -- d2 )
+            def!("marker", [Marker]),                     // ( "<spaces>name" -- )
+            def!("m+", Dup, 0, Less, DPlus),              // ( d1 n -- d2 )
+            def!("max", Max),                             // ( n1 n2 -- n3 )
+            def!("min", Min),                             // ( n1 n2 -- n3 )
             def!("mod", Mod),                             // ( n1 n2 -- rem)
+            def!("move", Move),                           // ( addr1 addr2 u -- )
+            def!("negate", 0, Swap, Minus),               // ( n1 -- n2 )
+            def!("or", Or),                               // ( n1 n2 -- n3 )
             def!("over", Swap, Dup, Rot, Swap),           // ( n1 n2 -- n1 n2 n1 )
+            def!("parse", Parse),                         // ( char "ccc<char>" -- c-addr u )
+            def!("parse-name", ParseName),                // ( "<spaces>name<space>" -- c-addr u )
+            def!("postpone", { Postpone }),               // ( "<spaces>name" -- )
+            def!("r>", RFrom),                            // ( -- n ) ( R: n -- )
+            def!("r@", RFetch),                           // ( -- n ) ( R: n -- n )
+            def!("recurse", { Recurse }),                 // ( -- )
+            def!("refill", Refill),                       // ( -- flag )
+            def!("replaces", Replaces),                   // ( c-addr1 u1 c-addr2 u2 -- )
+            def!("repeat", { Repeat }),                   // ( C: orig dest -- )
             def!("rot", Rot),                             // ( n1 n2 n3 -- n2 n3 n1 )
+            def!("rshift", RShift),                       // ( n1 u -- n2 )
+            def!("s\"", { SQuote }),                      // ( "ccc<quote>" -- ) ( -- c-addr u )
+            def!("s\\\"", { SBackslashQuote }),           // ( "ccc<quote>" -- ) ( -- c-addr u )
+            def!("s>d", Dup, 0, Less),                    // ( n -- d )
+            def!("search", Search),                       // ( s1 u1 s2 u2 -- s3 u3 flag )
+            def!("search-wordlist", SearchWordlist),      // ( c-addr u wid -- 0 | xt 1 | xt -1 )
+            def!("sign", Sign),                           // ( n -- )
+            def!("sliteral", { SLiteral }),               // ( c-addr1 u -- ) ( -- c-addr2 u )
+            def!("sm/rem", SmRem),                        // ( d n -- rem quot )
+            def!("source", Source),                       // ( -- c-addr u )
             def!("space", ' ', Emit),                     // ( -- )
             def!("spaces", Spaces),                       // ( n -- )
+            def!("state", (STATE * cell)),                // ( -- addr )
+            def!("substitute", Substitute),               // ( s1 u1 s2 u2 -- s2 u3 n )
             def!("swap", Swap),                           // ( n1 n2 -- n2 n1 )
-        ]))
+            def!("then", { Then }),                       // ( C: orig -- )
+            def!("to", { To }),                           // ( x "<spaces>name" -- )
+            def!("true", 0, Invert),                      // ( -- flag )
+            def!("type", Type),                           // ( c-addr u -- )
+            def!("u.", UDot),                             // ( u -- )
+            def!("u.r", UDotR),                           // ( u n -- )
+            def!("u<", ULess),                            // ( u1 u2 -- flag )
+            def!("u>", Swap, ULess),                      // ( u1 u2 -- flag )
+            def!("um*", UMStar),                          // ( u1 u2 -- ud )
+            def!("um/mod", UMSlashMod),                   // ( ud u1 -- u2 u3 )
+            def!("unescape", Unescape),                   // ( s1 u1 s2 -- s2 u2 )
+            def!("unloop", Unloop),                       // ( -- ) ( R: loop -- )
+            def!("until", { Until }),                     // ( C: dest -- ) ( x -- )
+            def!("value", [Value]),                       // ( x "<spaces>name" -- )
+            def!("variable", [Variable]),                 // ( "<spaces>name" -- )
+            def!("while", { While }),                     // ( C: dest -- orig dest ) ( x -- )
+            def!("within", Within),                       // ( n1 n2 n3 -- flag )
+            def!("word", ParseCounted),                   // ( char "<chars>ccc<char>" -- c-addr )
+            def!("xor", Xor),                             // ( n1 n2 -- n3 )
+        ]);
+
+        #[cfg(feature = "float")]
+        let dictionary = dictionary
+            .into_iter()
+            .chain([
+                def!("d>f", DToF),              // ( d -- ) ( F: -- r )
+                def!("f!", FStore),             // ( addr -- ) ( F: r -- )
+                def!("f*", FStar),              // ( F: r1 r2 -- r3 )
+                def!("f+", FPlus),              // ( F: r1 r2 -- r3 )
+                def!("f-", FMinus),             // ( F: r1 r2 -- r3 )
+                def!("f.", FDot),               // ( F: r -- )
+                def!("f/", FSlash),             // ( F: r1 r2 -- r3 )
+                def!("f0=", FZeroEquals),       // ( -- flag ) ( F: r -- )
+                def!("f<", FLess),              // ( -- flag ) ( F: r1 r2 -- )
+                def!("f>d", FToD),              // ( -- d ) ( F: r -- )
+                def!("f@", FFetch),             // ( addr -- ) ( F: -- r )
+                def!("fconstant", [FConstant]), // ( "<spaces>name" -- ) ( F: r -- )
+                def!("fdrop", FDrop),           // ( F: r -- )
+                def!("fdup", FDup),             // ( F: r -- r r )
+                def!("fexp", FExp),             // ( F: r1 -- r2 )
+                def!("fln", FLn),               // ( F: r1 -- r2 )
+                def!("fover", FOver),           // ( F: r1 r2 -- r1 r2 r1 )
+                def!("fsin", FSin),             // ( F: r1 -- r2 )
+                def!("fsqrt", FSqrt),           // ( F: r1 -- r2 )
+                def!("fswap", FSwap),           // ( F: r1 r2 -- r2 r1 )
+                def!("fvariable", [FVariable]), // ( "<spaces>name" -- )
+            ])
+            .collect();
+
+        Self::with_dictionary(config, dictionary)
     }
-}
 
-impl Machine {
-    fn with_dictionary(dictionary: HashMap<String, Vec<Token>>) -> Self {
-        Self {
-            dictionary: HashMap::from_iter(
-                dictionary
-                    .into_iter()
-                    .map(|(word, tokens)| (word, vec![tokens])),
-            ),
+    fn with_dictionary(mut config: Config, dictionary: HashMap<String, Vec<Token>>) -> Self {
+        let bits = config.cell_width.bits();
+        let system = SYSTEM_CELLS * bits as usize / 8 + PICTURE_SIZE;
+        let addressable = 1usize.checked_shl(bits).unwrap_or(usize::MAX);
+        config.memory_size = config.memory_size.min(addressable - system - INPUT_SIZE);
+        let mut machine = Self {
+            dictionary: HashMap::new(),
+            words: Vec::new(),
             stack: Vec::new(),
+            return_stack: Vec::new(),
+            #[cfg(feature = "float")]
+            float_stack: Vec::new(),
+            memory: vec![0; system + config.memory_size],
+            here: system,
+            transient: system + config.memory_size,
+            latest: 0,
+            created: None,
+            hold: system,
+            substitutions: HashMap::new(),
+            definition: None,
+            phrase: Compiler::default(),
+            commenting: false,
+            source: Box::new(std::iter::empty()),
+            pending: VecDeque::new(),
+            config,
+        };
+        machine.store(machine.system(BASE), 10);
+        for (word, tokens) in dictionary {
+            let immediate = matches!(tokens[..], [Token::Directive(_)]);
+            let xt = machine.define(word, tokens);
+            machine.words[xt].immediate = immediate;
+        }
+        machine
+    }
+
+    /// Supply the input read by programs, which otherwise find none.
+    pub fn set_source(&mut self, source: impl InputSource + 'static) {
+        self.source = Box::new(source);
+        self.pending.clear();
+    }
+
+    /// Read the next character of input, reading a new line if `key` has
+    /// used up the last one.
+    fn read_char(&mut self) -> Option<char> {
+        if self.pending.is_empty() {
+            let line = self.source.read_line()?;
+            self.pending.extend(line.chars().chain(['\n']));
+        }
+        self.pending.pop_front()
+    }
+
+    /// Read the rest of the line begun by `key`, if any, or else the next
+    /// line of input.
+    fn read_line(&mut self) -> Option<String> {
+        if self.pending.is_empty() {
+            return self.source.read_line();
+        }
+        let mut line: String = self.pending.drain(..).collect();
+        line.pop();
+        Some(line)
+    }
+
+    /// Reserve `bytes` of data space (or release them, if negative),
+    /// returning the address of the first byte reserved.
+    fn allot<'a>(&mut self, op: &'a str, bytes: isize) -> Result<usize, Error<'a>> {
+        let addr = self.here;
+        self.here = addr
+            .checked_add_signed(bytes)
+            .filter(|&here| (self.picture_end()..=self.data_end()).contains(&here))
+            .ok_or(Error::DataSpaceExhausted(op))?;
+        Ok(addr)
+    }
+
+    /// Reserve a single aligned cell of data space, returning its address.
+    fn allot_cell<'a>(&mut self, op: &'a str) -> Result<Cell, Error<'a>> {
+        self.here = self.aligned(self.here);
+        self.allot(op, self.cell_size() as isize)
+            .map(|addr| self.address(addr))
+    }
+
+    /// The `N` bytes at `addr`, if they lie within the data space.
+    #[cfg(feature = "float")]
+    fn bytes<const N: usize>(&mut self, addr: Cell) -> Option<&mut [u8; N]> {
+        let addr = self.offset(addr)?;
+        self.memory
+            .get_mut(addr..addr.checked_add(N)?)?
+            .try_into()
+            .ok()
+    }
+
+    /// The range of the `len` bytes at `addr`, if they lie within the data
+    /// space.
+    fn range(&self, addr: Cell, len: Cell) -> Option<Range<usize>> {
+        let addr = self.offset(addr)?;
+        let end = addr.checked_add(usize::try_from(len).ok()?)?;
+        (end <= self.memory.len()).then_some(addr..end)
+    }
+
+    /// Reserve data space for `bytes` and copy them into it, returning its
+    /// address.
+    fn allot_bytes<'a>(&mut self, op: &'a str, bytes: &[u8]) -> Result<Cell, Error<'a>> {
+        let addr = self.allot(op, bytes.len() as isize)?;
+        self.memory[addr..addr + bytes.len()].copy_from_slice(bytes);
+        Ok(self.address(addr))
+    }
+
+    /// Copy the bytes of a string literal into data space, returning its
+    /// address. A definition keeps its strings in reserved data space, but
+    /// an interpreted string is transient, and is overwritten by later ones
+    /// once the free data space between them and `here` runs out.
+    fn string_bytes<'a>(&mut self, op: &'a str, bytes: &[u8]) -> Result<Cell, Error<'a>> {
+        if self.compiling() {
+            return self.allot_bytes(op, bytes);
+        }
+        let fits = |end: usize| {
+            end.checked_sub(bytes.len())
+                .filter(|&addr| addr >= self.here)
+        };
+        let addr = fits(self.transient)
+            .or_else(|| fits(self.data_end()))
+            .ok_or(Error::DataSpaceExhausted(op))?;
+        self.memory[addr..addr + bytes.len()].copy_from_slice(bytes);
+        self.transient = addr;
+        Ok(self.address(addr))
+    }
+
+    /// `addr` as a cell, to be pushed by words that return an address.
+    fn address(&self, addr: usize) -> Cell {
+        self.wrap(addr as i128)
+    }
+
+    /// The index in memory of the address held in a cell, which is unsigned.
+    fn offset(&self, addr: Cell) -> Option<usize> {
+        usize::try_from(self.unsigned(addr)).ok()
+    }
+
+    /// The cell at `addr`, if it lies within the data space.
+    fn fetch(&self, addr: Cell) -> Option<Cell> {
+        let size = self.cell_size();
+        let addr = self.offset(addr)?;
+        let mut bytes = [0; size_of::<Cell>()];
+        bytes[..size].copy_from_slice(self.memory.get(addr..addr.checked_add(size)?)?);
+        Some(self.wrap(Cell::from_le_bytes(bytes).into()))
+    }
+
+    /// Store `n` in the cell at `addr`, if it lies within the data space.
+    fn store(&mut self, addr: Cell, n: Cell) -> Option<()> {
+        let size = self.cell_size();
+        let addr = self.offset(addr)?;
+        self.memory
+            .get_mut(addr..addr.checked_add(size)?)?
+            .copy_from_slice(&n.to_le_bytes()[..size]);
+        Some(())
+    }
+
+    /// The number of bits in a cell.
+    fn bits(&self) -> u32 {
+        self.config.cell_width.bits()
+    }
+
+    /// The size of a cell in bytes.
+    fn cell_size(&self) -> usize {
+        self.bits() as usize / 8
+    }
+
+    /// Round `addr` up to the next cell boundary.
+    fn aligned(&self, addr: usize) -> usize {
+        addr.next_multiple_of(self.cell_size())
+    }
+
+    /// Truncate `n` to a cell.
+    fn wrap(&self, n: i128) -> Cell {
+        let shift = i128::BITS - self.bits();
+        ((n << shift) >> shift) as Cell
+    }
+
+    /// `n` as a cell, if it fits in one.
+    fn narrow(&self, n: i128) -> Option<Cell> {
+        let cell = self.wrap(n);
+        (i128::from(cell) == n).then_some(cell)
+    }
+
+    /// `d` as a double-cell number, if it fits in one.
+    fn narrow_double(&self, d: Double) -> Option<Double> {
+        let shift = i128::BITS - 2 * self.bits();
+        ((d << shift) >> shift == d).then_some(d)
+    }
+
+    /// The bits of `n` as an unsigned cell.
+    fn unsigned(&self, n: Cell) -> u64 {
+        n as u64 & u64::MAX >> (u64::BITS - self.bits())
+    }
+
+    /// The bits of `d` as an unsigned double-cell number.
+    fn unsigned_double(&self, d: Double) -> u128 {
+        d as u128 & u128::MAX >> (u128::BITS - 2 * self.bits())
+    }
+
+    /// The address of the given system variable.
+    fn system(&self, var: usize) -> Cell {
+        (var * self.cell_size()) as Cell
+    }
+
+    /// The address just past the data space, where the input buffer begins.
+    fn data_end(&self) -> usize {
+        self.picture_end() + self.config.memory_size
+    }
+
+    /// The address just past the pictured numeric output buffer.
+    fn picture_end(&self) -> usize {
+        SYSTEM_CELLS * self.cell_size() + PICTURE_SIZE
+    }
+
+    /// Add `c` to the start of the pictured numeric output.
+    fn hold<'a>(&mut self, op: &'a str, c: u8) -> Result<(), Error<'a>> {
+        if self.hold == self.picture_end() - PICTURE_SIZE {
+            return Err(Error::PictureOverflow(op));
+        }
+        self.hold -= 1;
+        self.memory[self.hold] = c;
+        Ok(())
+    }
+
+    /// Add the least significant digit of `ud` in `radix` to the pictured
+    /// numeric output, returning the remaining digits.
+    fn hold_digit<'a>(&mut self, op: &'a str, ud: u128, radix: u32) -> Result<u128, Error<'a>> {
+        let radix = u128::from(radix);
+        self.hold(op, DIGITS[(ud % radix) as usize])?;
+        Ok(ud / radix)
+    }
+
+    /// Add every digit of `ud` in `radix` to the pictured numeric output.
+    fn hold_digits<'a>(&mut self, op: &'a str, mut ud: u128, radix: u32) -> Result<(), Error<'a>> {
+        loop {
+            ud = self.hold_digit(op, ud, radix)?;
+            if ud == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Format `ud`, preceded by a minus sign if `negative`, in the pictured
+    /// numeric output buffer, as `<# #s sign #>` would.
+    fn picture<'a>(
+        &mut self,
+        op: &'a str,
+        ud: u128,
+        negative: bool,
+        radix: u32,
+    ) -> Result<String, Error<'a>> {
+        self.hold = self.picture_end();
+        self.hold_digits(op, ud, radix)?;
+        if negative {
+            self.hold(op, b'-')?;
+        }
+        let bytes = &self.memory[self.hold..self.picture_end()];
+        Ok(String::from_utf8_lossy(bytes).into_owned())
+    }
+
+    /// The number base, if `base` holds one that is supported.
+    fn radix(&self) -> Option<u32> {
+        self.fetch(self.system(BASE))
+            .and_then(|n| u32::try_from(n).ok())
+            .filter(|radix| (2..=36).contains(radix))
+    }
+
+    /// Run `parse` on the input from the position held in `>in`, since a
+    /// program may have changed it, and then update `>in` to match.
+    fn parse<T>(&mut self, input: &mut Input, parse: impl FnOnce(&mut Input) -> T) -> T {
+        self.resume(input);
+        let parsed = parse(input);
+        self.store(self.system(TO_IN), input.pos as Cell);
+        parsed
+    }
+
+    /// Move the parse position of `input` to the one held in `>in`.
+    fn resume(&self, input: &mut Input) {
+        if let Some(pos) = self
+            .fetch(self.system(TO_IN))
+            .and_then(|n| usize::try_from(n).ok())
+        {
+            input.seek(pos);
         }
     }
 
+    /// Push the address and length of the text at `range` in the input
+    /// buffer.
+    fn push_parsed(&mut self, range: Range<usize>) {
+        self.stack.push(self.address(self.data_end() + range.start));
+        self.stack.push(range.len() as Cell);
+    }
+
+    /// Combine the low and high cells of a double-cell number.
+    fn double(&self, lo: Cell, hi: Cell) -> Double {
+        Double::from(hi) << self.bits() | Double::from(self.unsigned(lo))
+    }
+
+    /// Add `tokens` to the word table and make it the latest definition of
+    /// `word`, returning its execution token.
+    fn define(&mut self, word: String, tokens: Tokens) -> usize {
+        let xt = self.words.len();
+        self.words.push(Definition {
+            tokens,
+            immediate: false,
+            body: None,
+        });
+        self.dictionary.entry(word).or_default().push(xt);
+        self.latest = xt;
+        xt
+    }
+
+    /// Evaluate `phrase`, returning the output it produced.
     pub fn eval<'a>(&mut self, phrase: &'a str) -> Result<String, Error<'a>> {
-        if let Some(def) = phrase.strip_prefix(':') {
-            self.eval_def(def).map(|()| String::new())
-        } else if let Some(def) = phrase.strip_prefix("forget ") {
-            self.eval_undef(def).map(|()| String::new())
-        } else if let Some(def) = phrase.strip_prefix("marker ") {
-            self.eval_marker(def).map(|()| String::new())
-        } else {
-            self.eval_expr(phrase)
+        let mut out = Vec::new();
+        self.eval_to(phrase, &mut out)?;
+        Ok(String::from_utf8_lossy(&out).into_owned())
+    }
+
+    /// Evaluate `phrase`, writing its output to `out` as it is produced.
+    pub fn eval_to<'a>(&mut self, phrase: &'a str, out: &mut dyn Write) -> Result<(), Error<'a>> {
+        let result = self.interpret(phrase, out);
+        if result.is_err() {
+            // Abandon anything left unfinished by the error
+            self.definition = None;
+            self.phrase = Compiler::default();
+            self.commenting = false;
+            self.store(self.system(STATE), 0);
         }
+        result
     }
 
-    fn eval_def<'a>(&mut self, phrase: &'a str) -> Result<(), Error<'a>> {
-        let mut words = phrase.split_ascii_whitespace();
-        let name = words
-            .next()
-            .ok_or(Error::Static("no name specified for definition"))?;
+    fn interpret<'a>(&mut self, phrase: &str, out: &mut dyn Write) -> Result<(), Error<'a>> {
+        // Make the input buffer addressable, just past the data space
+        self.memory.truncate(self.data_end());
+        self.memory.extend_from_slice(phrase.as_bytes());
+
+        let mut input = Input::new(phrase);
+        if self.commenting {
+            let range = input.parse(')');
+            self.commenting = range.end == input.pos;
+        }
+        while let Some(word) = input.parse_name() {
+            self.compile(&word, &mut input, out)?;
+
+            // Run what has been compiled so far, unless it is in the middle
+            // of a control structure
+            if !self.compiling() && self.phrase.control.is_empty() {
+                let tokens = std::mem::take(&mut self.phrase.tokens);
+                self.run(&tokens, &mut input, out)?;
+            }
+        }
+        std::mem::take(&mut self.phrase).finish().map(|_| ())
+    }
+
+    /// Execute `tokens`, keeping `>in` in step with the parse position of
+    /// `input`.
+    fn run<'a>(
+        &mut self,
+        tokens: &[Token],
+        input: &mut Input,
+        out: &mut dyn Write,
+    ) -> Result<(), Error<'a>> {
+        self.store(self.system(TO_IN), input.pos as Cell);
+        let result = self.execute(tokens, input, out);
+        self.resume(input);
+        if result.is_err() {
+            // Loop parameters left behind by an aborted word are meaningless
+            self.return_stack.clear();
+        }
+        result
+    }
+
+    /// Whether a definition is being compiled, rather than interrupted by
+    /// `[` or finished by `;`.
+    pub fn compiling(&self) -> bool {
+        self.defining() && self.fetch(self.system(STATE)) != Some(0)
+    }
 
-        let tokens = self.tokenize(words)?;
-        self.dictionary.entry(name.into()).or_default().push(tokens);
+    /// Whether a definition has been begun but not yet finished by `;`,
+    /// even if interrupted by `[`.
+    pub fn defining(&self) -> bool {
+        self.definition.is_some()
+    }
+
+    /// Start compiling a definition of `name`, reserving its execution token
+    /// so that words defined meanwhile do not take it.
+    fn begin_definition(&mut self, name: String) {
+        let xt = self.words.len();
+        self.words.push(Definition {
+            tokens: Vec::new(),
+            immediate: false,
+            body: None,
+        });
+        self.definition = Some(Compiler {
+            name: Some((name, xt)),
+            ..Default::default()
+        });
+        self.store(self.system(STATE), -1);
+    }
+
+    /// Finish the definition being compiled.
+    fn end_definition<'a>(&mut self) -> Result<(), Error<'a>> {
+        let mut definition = self
+            .definition
+            .take()
+            .ok_or(Error::Static(";: outside of a definition"))?;
+        self.store(self.system(STATE), 0);
+        let (name, xt) = definition.name.take().expect("definitions are named");
+        self.words[xt].tokens = definition.finish()?;
+        self.dictionary.entry(name).or_default().push(xt);
+        self.latest = xt;
         Ok(())
     }
 
-    fn eval_undef<'a>(&mut self, word: &'a str) -> Result<(), Error<'a>> {
+    /// What words are compiled into: the definition being compiled, or else
+    /// the phrase being interpreted.
+    fn compiler(&mut self) -> &mut Compiler {
+        let compiling = self.compiling();
+        match &mut self.definition {
+            Some(definition) if compiling => definition,
+            _ => &mut self.phrase,
+        }
+    }
+
+    /// Remove the latest definition of `word`.
+    fn forget<'a>(&mut self, word: &str) -> Result<(), Error<'a>> {
         let def = self
             .dictionary
             .get_mut(word)
-            .ok_or(Error::UndefinedWord(word))?;
+            .ok_or(Error::UndefinedWord(word.into()))?;
         if def.len() == 1 {
             self.dictionary.remove(word);
         } else {
@@ -111,20 +888,31 @@ impl Machine {
         Ok(())
     }
 
-    fn eval_marker<'a>(&mut self, label: &'a str) -> Result<(), Error<'a>> {
-        // Append a marker to every definition
+    /// Define `label` as a marker which, when executed, removes every
+    /// definition made since and releases the data space reserved since.
+    fn mark(&mut self, label: String) {
+        let xt = self.words.len();
+        self.words.push(Definition {
+            tokens: vec![Token::Marker(label.clone(), self.here)],
+            immediate: false,
+            body: None,
+        });
+
+        // Append the marker to every definition
         for defs in self.dictionary.values_mut() {
-            defs.push(vec![Token::Marker(label.into())])
+            defs.push(xt)
         }
 
         // Add the null definition
-        self.dictionary
-            .insert(label.into(), vec![vec![Token::Marker(label.into())]]);
-
-        Ok(())
+        self.dictionary.insert(label, vec![xt]);
     }
 
-    fn eval_expr<'a>(&mut self, phrase: &'a str) -> Result<String, Error<'a>> {
+    fn execute<'a>(
+        &mut self,
+        tokens: &[Token],
+        input: &mut Input,
+        out: &mut dyn Write,
+    ) -> Result<(), Error<'a>> {
         macro_rules! pop {
             ($op:literal) => {
                 pop!($op, 0)
@@ -160,180 +948,2674 @@ impl Machine {
             }}
         }
 
-        macro_rules! output {
-            ($content:expr, $output:ident) => {
-                $output = $output + $content + " "
+        macro_rules! overflow {
+            ($name:literal, $checked:expr, $wrapping:expr) => {
+                match self.config.arithmetic {
+                    Arithmetic::Checked => $checked.ok_or(Error::ArithmeticOverflow($name))?,
+                    Arithmetic::Wrapping => $wrapping,
+                }
             };
         }
 
-        self.tokenize(phrase.split_ascii_whitespace())?
-            .into_iter()
-            .try_fold(String::new(), |mut out, token| -> Result<String, Error> {
-                use Token::*;
-                use Word::*;
-
-                match token {
-                    Builtin(Dot) => output!(&pop!("dot").to_string(), out),
-                    Builtin(Drop) => {
-                        pop!("drop");
-                    }
-                    Builtin(Dup) => self.stack.push(peek!("dup")),
-                    Builtin(Emit) => match u32::try_from(pop!("emit")) {
-                        Ok(val) => output!(
-                            &char::from_u32(val)
-                                .ok_or(Error::UnicodeInvalid(val))?
-                                .to_string(),
-                            out
-                        ),
-                        _ => return Err(Error::Static("emit: out of bounds")),
-                    },
-                    Builtin(Minus) => apply!("minus", -),
-                    Builtin(Mod) => apply!("mod", %),
-                    Builtin(Plus) => apply!("plus", +),
-                    Builtin(Rot) => {
-                        let n = pop!("rot", 2);
-                        self.stack.push(n);
-                    }
-                    Builtin(Slash) => apply!("slash", /),
-                    Builtin(SlashMod) => {
-                        let b = pop!("slash-mod");
-                        let a = pop!("slash-mod");
-                        self.stack.push(a % b);
-                        self.stack.push(a / b);
-                    }
-                    Builtin(StackPrint) => {
-                        output!(
-                            &format!(
-                                "<{}> {}",
-                                self.stack.len(),
-                                self.stack
-                                    .iter()
-                                    .map(|n| n.to_string())
-                                    .collect::<Vec<String>>()
-                                    .join(" ")
-                            ),
-                            out
-                        )
-                    }
-                    Builtin(Spaces) => output!(&" ".repeat(pop!("spaces") as usize), out),
-                    Builtin(Star) => apply!("star", *),
-                    Builtin(Swap) => {
-                        let n = pop!("swap", 1);
-                        self.stack.push(n);
-                    }
-                    Builtin(TwoOver) => {
-                        let n1 = peek!("2over", 3);
-                        let n2 = peek!("2over", 2);
-                        self.stack.push(n1);
-                        self.stack.push(n2);
-                    }
-                    Builtin(TwoSwap) => {
-                        let n1 = pop!("2swap", 3);
-                        let n2 = pop!("2swap", 2);
-                        self.stack.push(n1);
-                        self.stack.push(n2);
-                    }
-                    Marker(ref marker) => {
-                        // Walk back every definition until the marker
-                        // is found or the history is exhausted
-                        for defs in self.dictionary.values_mut() {
-                            while let Some(def) = defs.pop() {
-                                match def.first() {
-                                    Some(Marker(m)) if m == marker => break,
-                                    _ => {}
-                                }
-                            }
-                        }
-                    }
-                    Number(n) => self.stack.push(n),
-                }
+        macro_rules! narrow {
+            ($name:literal, $n:expr) => {{
+                let n: i128 = $n;
+                overflow!($name, self.narrow(n), self.wrap(n))
+            }};
+        }
 
-                Ok(out)
-            })
-    }
+        macro_rules! narrow_double {
+            ($name:literal, $checked:expr, $wrapping:expr) => {
+                overflow!(
+                    $name,
+                    $checked.and_then(|d| self.narrow_double(d)),
+                    $wrapping
+                )
+            };
+        }
 
-    fn tokenize<'a, I: Iterator<Item = &'a str>>(
-        &self,
-        strings: I,
-    ) -> Result<Vec<Token>, Error<'a>> {
-        let mut comment = false;
-        let mut tokens = Vec::new();
-        for string in strings {
-            match (string, comment) {
-                ("(", true) => Err(Error::Static("unbalanced opening comment"))?,
-                (")", false) => Err(Error::Static("unbalanced closing comment"))?,
-                ("(", false) | (")", true) => {
-                    comment = !comment;
-                    continue;
+        macro_rules! arithmetic {
+            ($name:literal, $op:tt) => {{
+                let o = pop!($name);
+                let n = pop!($name);
+                let r = narrow!($name, i128::from(n) $op i128::from(o));
+                self.stack.push(r)
+            }};
+        }
+
+        macro_rules! divide {
+            ($name:literal, $n:expr, $d:expr, $division:expr) => {{
+                let d = $d;
+                if d == 0 {
+                    return Err(Error::DivisionByZero($name));
                 }
-                (_, true) => continue,
-                _ => {}
-            }
+                let (r, q) = divide($n.into(), d.into(), $division);
+                (r as Cell, narrow!($name, q))
+            }};
+        }
 
-            match string.parse::<i32>() {
-                Ok(n) => tokens.push(Token::Number(n)),
-                _ => tokens.extend_from_slice(
-                    self.dictionary
-                        .get(string)
-                        .and_then(|defs| {
-                            // Find the latest definition, skipping markers
-                            defs.iter()
-                                .rev()
-                                .filter(|def| match def.first() {
-                                    Some(Token::Marker(m)) if m != string => false,
-                                    _ => true,
-                                })
-                                .next()
-                        })
-                        .ok_or(Error::UndefinedWord(string))?,
-                ),
-            }
+        macro_rules! pop_double {
+            ($op:literal) => {{
+                let hi = pop!($op);
+                let lo = pop!($op);
+                self.double(lo, hi)
+            }};
         }
-        Ok(tokens)
-    }
-}
 
-pub enum Error<'a> {
-    Static(&'a str),
-    UndefinedWord(&'a str),
-    UnicodeInvalid(u32),
-}
+        macro_rules! divide_double {
+            ($name:literal, $division:expr) => {{
+                let d = pop!($name);
+                divide!($name, pop_double!($name), d, $division)
+            }};
+        }
 
-impl<'a> fmt::Display for Error<'a> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        use Error::*;
+        macro_rules! scale {
+            ($name:literal) => {{
+                let d = pop!($name);
+                let n2 = pop!($name);
+                let n1 = pop!($name);
+                let n = i128::from(n1) * i128::from(n2);
+                divide!($name, n, d, self.config.division)
+            }};
+        }
 
-        match *self {
-            Static(err) => f.write_str(err),
-            UndefinedWord(w) => write!(f, "undefined word '{w}'"),
-            UnicodeInvalid(v) => write!(f, "emit: invalid unicode {v:#04x}"),
+        macro_rules! compare {
+            ($name:literal, $op:tt) => {{
+                let o = pop!($name);
+                let r = pop!($name) $op o;
+                self.stack.push(-Cell::from(r))
+            }}
         }
-    }
-}
 
-#[derive(Clone, Copy)]
-enum Token {
-    Builtin(Word),
-    Marker(String),
-    Number(i32),
-}
+        macro_rules! fetch {
+            ($op:literal, $addr:expr) => {{
+                let addr = $addr;
+                self.fetch(addr).ok_or(Error::AddressInvalid($op, addr))?
+            }};
+        }
 
-#[derive(Clone, Copy)]
-enum Word {
-    Dot,
-    Drop,
-    Dup,
-    Emit,
-    Minus,
-    Mod,
-    Plus,
-    Rot,
-    Slash,
-    SlashMod,
-    Spaces,
-    StackPrint,
-    Star,
-    Swap,
-    TwoOver,
-    TwoSwap,
+        macro_rules! store {
+            ($op:literal, $addr:expr, $n:expr) => {{
+                let addr = $addr;
+                let n: Cell = $n;
+                self.store(addr, n)
+                    .ok_or(Error::AddressInvalid($op, addr))?;
+            }};
+        }
+
+        macro_rules! byte {
+            ($op:literal, $addr:expr) => {{
+                let addr = $addr;
+                self.offset(addr)
+                    .and_then(|a| self.memory.get_mut(a))
+                    .ok_or(Error::AddressInvalid($op, addr))?
+            }};
+        }
+
+        macro_rules! radix {
+            ($op:literal) => {
+                self.radix()
+                    .ok_or(Error::Static(concat!($op, ": invalid base")))?
+            };
+        }
+
+        macro_rules! range {
+            ($op:literal, $addr:expr, $len:expr) => {{
+                let addr = $addr;
+                self.range(addr, $len)
+                    .ok_or(Error::AddressInvalid($op, addr))?
+            }};
+        }
+
+        macro_rules! output {
+            ($( $arg:tt )+) => {
+                write!(out, $( $arg )+)
+                    .and_then(|()| out.flush())
+                    .map_err(Error::Output)?
+            };
+        }
+
+        macro_rules! pad {
+            ($n:expr) => {
+                write_spaces(out, $n)
+                    .and_then(|()| out.flush())
+                    .map_err(Error::Output)?
+            };
+        }
+
+        #[cfg(feature = "float")]
+        macro_rules! fpop {
+            ($op:literal) => {
+                self.float_stack
+                    .pop()
+                    .ok_or(Error::Static(concat!($op, ": float stack underflow")))?
+            };
+        }
+
+        #[cfg(feature = "float")]
+        macro_rules! fapply {
+            ($name:literal, $op:tt) => {{
+                let o = fpop!($name);
+                let r = fpop!($name) $op o;
+                self.float_stack.push(r)
+            }};
+        }
+
+        #[cfg(feature = "float")]
+        macro_rules! funary {
+            ($name:literal, $f:ident) => {{
+                let r = fpop!($name);
+                self.float_stack.push(r.$f())
+            }};
+        }
+
+        macro_rules! rpop {
+            ($op:literal) => {
+                self.return_stack
+                    .pop()
+                    .ok_or(Error::Static(concat!($op, ": return stack underflow")))?
+            };
+        }
+
+        macro_rules! rpeek {
+            ($op:literal, $n:literal) => {
+                self.return_stack[self
+                    .return_stack
+                    .len()
+                    .checked_sub($n + 1)
+                    .ok_or(Error::Static(concat!($op, ": return stack underflow")))?]
+            };
+        }
+
+        // The word being executed (or `None` for the top-level phrase), the
+        // program counter within it, and the call frames of its callers
+        let mut current: Option<usize> = None;
+        let mut pc = 0;
+        let mut frames = Vec::new();
+
+        macro_rules! call {
+            ($xt:expr, $at:expr) => {{
+                if frames.len() == MAX_CALL_DEPTH {
+                    return Err(Error::Static("return stack overflow"));
+                }
+                frames.push((current, pc));
+                current = Some($xt);
+                pc = $at;
+            }};
+        }
+
+        loop {
+            use Token::*;
+            use Word::*;
+
+            let body = match current {
+                Some(xt) => &self.words[xt].tokens,
+                None => tokens,
+            };
+            let Some(token) = body.get(pc).cloned() else {
+                match frames.pop() {
+                    Some((xt, ret)) => {
+                        current = xt;
+                        pc = ret;
+                        continue;
+                    }
+                    None => break,
+                }
+            };
+
+            pc += 1;
+            match token {
+                Builtin(Abs) => {
+                    let n = pop!("abs");
+                    let r = narrow!("abs", i128::from(n).abs());
+                    self.stack.push(r);
+                }
+                Builtin(Accept) => {
+                    let len = pop!("accept");
+                    let addr = pop!("accept");
+                    let range = range!("accept", addr, len);
+                    let line = self.read_line().unwrap_or_default();
+                    let line = &line.as_bytes()[..line.len().min(range.len())];
+                    self.memory[range.start..range.start + line.len()].copy_from_slice(line);
+                    self.stack.push(line.len() as Cell);
+                }
+                Builtin(Align) => self.here = self.aligned(self.here),
+                Builtin(Aligned) => {
+                    let addr = pop!("aligned");
+                    let cell = self.cell_size() as Cell;
+                    let r = self.wrap(i128::from(addr) + i128::from(cell) - 1) & -cell;
+                    self.stack.push(r);
+                }
+                Builtin(Allot) => {
+                    let n = pop!("allot");
+                    self.allot("allot", n as isize)?;
+                }
+                Builtin(And) => apply!("and", &),
+                Builtin(CComma) => {
+                    let c = pop!("c,");
+                    let addr = self.allot("c,", 1)?;
+                    self.memory[addr] = c as u8;
+                }
+                Builtin(CFetch) => {
+                    let c = *byte!("c@", pop!("c@"));
+                    self.stack.push(c.into());
+                }
+                Builtin(CStore) => {
+                    let addr = pop!("c!");
+                    let c = pop!("c!");
+                    *byte!("c!", addr) = c as u8;
+                }
+                Builtin(Colon) => {
+                    let name = self
+                        .parse(input, Input::parse_name)
+                        .ok_or(Error::NameMissing(":"))?;
+                    self.begin_definition(name);
+                }
+                Builtin(CompileComma) => {
+                    let xt = pop!("compile,");
+                    let xt = self
+                        .xt(xt)
+                        .ok_or(Error::Static("compile,: invalid execution token"))?;
+                    self.definition
+                        .as_mut()
+                        .ok_or(Error::Static("compile,: outside of a definition"))?
+                        .tokens
+                        .push(Call(xt));
+                }
+                Builtin(Count) => {
+                    let addr = pop!("count");
+                    let len = *byte!("count", addr);
+                    self.stack.push(addr.wrapping_add(1));
+                    self.stack.push(len.into());
+                }
+                Builtin(CMove) => {
+                    let len = pop!("cmove");
+                    let dst = range!("cmove", pop!("cmove"), len);
+                    let src = range!("cmove", pop!("cmove"), len);
+
+                    // Copy a byte at a time, so an overlapping destination
+                    // sees the bytes already copied
+                    for (d, s) in dst.zip(src) {
+                        self.memory[d] = self.memory[s];
+                    }
+                }
+                Builtin(CMoveUp) => {
+                    let len = pop!("cmove>");
+                    let dst = range!("cmove>", pop!("cmove>"), len);
+                    let src = range!("cmove>", pop!("cmove>"), len);
+                    for (d, s) in dst.zip(src).rev() {
+                        self.memory[d] = self.memory[s];
+                    }
+                }
+                Builtin(Compare) => {
+                    let len2 = pop!("compare");
+                    let s2 = range!("compare", pop!("compare"), len2);
+                    let len1 = pop!("compare");
+                    let s1 = range!("compare", pop!("compare"), len1);
+                    let order = self.memory[s1].cmp(&self.memory[s2]);
+                    self.stack.push(order as Cell);
+                }
+                Builtin(Comma) => {
+                    let n = pop!(",");
+                    let addr = self.allot(",", self.cell_size() as isize)?;
+                    store!(",", self.address(addr), n);
+                }
+                Builtin(DAbs) => {
+                    let d = pop_double!("dabs");
+                    let r = narrow_double!("dabs", d.checked_abs(), d.wrapping_abs());
+                    self.push_double(r);
+                }
+                Builtin(DashTrailing) => {
+                    let len = pop!("-trailing");
+                    let addr = peek!("-trailing");
+                    let range = range!("-trailing", addr, len);
+                    let spaces = self.memory[range]
+                        .iter()
+                        .rev()
+                        .take_while(|&&b| b == b' ')
+                        .count();
+                    self.stack.push(len - spaces as Cell);
+                }
+                Builtin(DDot) => {
+                    let d = pop_double!("d.");
+                    let s = self.picture("d.", d.unsigned_abs(), d < 0, radix!("d."))?;
+                    output!("{s} ")
+                }
+                Builtin(DDotR) => {
+                    let width = pop!("d.r").max(0) as u64;
+                    let d = pop_double!("d.r");
+                    let s = self.picture("d.r", d.unsigned_abs(), d < 0, radix!("d.r"))?;
+                    pad!(width.saturating_sub(s.len() as u64));
+                    output!("{s}");
+                }
+                Builtin(DEquals) => {
+                    let d2 = pop_double!("d=");
+                    let d1 = pop_double!("d=");
+                    self.stack.push(-Cell::from(d1 == d2));
+                }
+                Builtin(DLess) => {
+                    let d2 = pop_double!("d<");
+                    let d1 = pop_double!("d<");
+                    self.stack.push(-Cell::from(d1 < d2));
+                }
+                Builtin(DMinus) => {
+                    let d2 = pop_double!("d-");
+                    let d1 = pop_double!("d-");
+                    let r = narrow_double!("d-", d1.checked_sub(d2), d1.wrapping_sub(d2));
+                    self.push_double(r);
+                }
+                Builtin(DNegate) => {
+                    let d = pop_double!("dnegate");
+                    let r = narrow_double!("dnegate", d.checked_neg(), d.wrapping_neg());
+                    self.push_double(r);
+                }
+                Builtin(DPlus) => {
+                    let d2 = pop_double!("d+");
+                    let d1 = pop_double!("d+");
+                    let r = narrow_double!("d+", d1.checked_add(d2), d1.wrapping_add(d2));
+                    self.push_double(r);
+                }
+                Builtin(DToS) => {
+                    let d = pop_double!("d>s");
+                    let n = narrow!("d>s", d);
+                    self.stack.push(n);
+                }
+                Builtin(DTwoSlash) => {
+                    let d = pop_double!("d2/");
+                    self.push_double(d >> 1);
+                }
+                Builtin(DTwoStar) => {
+                    let d = pop_double!("d2*");
+                    let r = narrow_double!("d2*", d.checked_mul(2), d << 1);
+                    self.push_double(r);
+                }
+                Builtin(Dot) => {
+                    let n = pop!("dot");
+                    let s = self.picture("dot", n.unsigned_abs().into(), n < 0, radix!("dot"))?;
+                    output!("{s} ")
+                }
+                Builtin(DotR) => {
+                    let width = pop!(".r").max(0) as u64;
+                    let n = pop!(".r");
+                    let s = self.picture(".r", n.unsigned_abs().into(), n < 0, radix!(".r"))?;
+                    pad!(width.saturating_sub(s.len() as u64));
+                    output!("{s}");
+                }
+                Builtin(Drop) => {
+                    pop!("drop");
+                }
+                Builtin(Dup) => self.stack.push(peek!("dup")),
+                Builtin(Equals) => compare!("equals", ==),
+                Builtin(Fetch) => {
+                    let n = fetch!("@", pop!("@"));
+                    self.stack.push(n);
+                }
+                Builtin(Greater) => compare!("greater-than", >),
+                Builtin(Fill) => {
+                    let c = pop!("fill");
+                    let len = pop!("fill");
+                    let range = range!("fill", pop!("fill"), len);
+                    self.memory[range].fill(c as u8);
+                }
+                Builtin(Forget) => {
+                    let name = self
+                        .parse(input, Input::parse_name)
+                        .ok_or(Error::NameMissing("forget"))?;
+                    self.forget(&name)?;
+                }
+                Builtin(Find) => {
+                    let addr = pop!("find");
+                    let len = *byte!("find", addr);
+                    let range = range!("find", addr.wrapping_add(1), len.into());
+                    match self.find_bytes(&self.memory[range]) {
+                        Some((xt, flag)) => {
+                            self.stack.push(xt as Cell);
+                            self.stack.push(flag);
+                        }
+                        None => {
+                            self.stack.push(addr);
+                            self.stack.push(0);
+                        }
+                    }
+                }
+                Builtin(FmMod) => {
+                    let (r, q) = divide_double!("fm/mod", Division::Floored);
+                    self.stack.push(r);
+                    self.stack.push(q);
+                }
+                Builtin(Here) => self.stack.push(self.address(self.here)),
+                Builtin(Hold) => {
+                    let c = pop!("hold");
+                    self.hold("hold", c as u8)?;
+                }
+                Builtin(I) => self.stack.push(rpeek!("i", 0)),
+                Builtin(Immediate) => {
+                    self.words[self.latest].immediate = true;
+                }
+                Builtin(Invert) => {
+                    let n = pop!("invert");
+                    self.stack.push(!n);
+                }
+                Builtin(J) => self.stack.push(rpeek!("j", 2)),
+                Builtin(Key) => {
+                    let c = self.read_char().ok_or(Error::Static("key: end of input"))?;
+                    self.stack.push(u32::from(c).into());
+                }
+                Builtin(KeyQuestion) => {
+                    let ready = !self.pending.is_empty() || self.source.ready();
+                    self.stack.push(-Cell::from(ready));
+                }
+                Builtin(Emit) => match u32::try_from(pop!("emit")) {
+                    Ok(val) => output!(
+                        "{} ",
+                        char::from_u32(val).ok_or(Error::UnicodeInvalid(val))?
+                    ),
+                    _ => return Err(Error::Static("emit: out of bounds")),
+                },
+                Builtin(Execute) => {
+                    let xt = pop!("execute");
+                    let xt = self
+                        .xt(xt)
+                        .ok_or(Error::Static("execute: invalid execution token"))?;
+                    call!(xt, 0);
+                }
+                Builtin(LShift) => {
+                    let u = pop!("lshift");
+                    let n = pop!("lshift");
+                    let r = match u32::try_from(u) {
+                        Ok(u) if u < self.bits() => self.wrap(i128::from(n) << u),
+                        _ => 0,
+                    };
+                    self.stack.push(r);
+                }
+                Builtin(Less) => compare!("less-than", <),
+                Builtin(LessNumberSign) => self.hold = self.picture_end(),
+                Builtin(Move) => {
+                    let len = pop!("move");
+                    let dst = range!("move", pop!("move"), len);
+                    let src = range!("move", pop!("move"), len);
+                    self.memory.copy_within(src, dst.start);
+                }
+                Builtin(MStar) => {
+                    let n2 = pop!("m*");
+                    let n1 = pop!("m*");
+                    self.push_double(i128::from(n1) * i128::from(n2));
+                }
+                Builtin(MStarSlash) => {
+                    let n2 = pop!("m*/");
+                    let n1 = pop!("m*/");
+                    let d = pop_double!("m*/");
+                    if n2 == 0 {
+                        return Err(Error::DivisionByZero("m*/"));
+                    }
+                    let (q, fits) = scale(d, n1, n2, self.config.division);
+                    let r = narrow_double!("m*/", fits.then_some(q), q);
+                    self.push_double(r);
+                }
+                Builtin(Max) => {
+                    let n2 = pop!("max");
+                    let n1 = pop!("max");
+                    self.stack.push(n1.max(n2));
+                }
+                Builtin(Min) => {
+                    let n2 = pop!("min");
+                    let n1 = pop!("min");
+                    self.stack.push(n1.min(n2));
+                }
+                Builtin(Minus) => arithmetic!("minus", -),
+                Builtin(Mod) => {
+                    let d = pop!("mod");
+                    let n = pop!("mod");
+                    let (r, _) = divide!("mod", n, d, self.config.division);
+                    self.stack.push(r);
+                }
+                Builtin(NumberSign) => {
+                    let ud = pop_double!("#");
+                    let radix = radix!("#");
+                    let ud = self.hold_digit("#", self.unsigned_double(ud), radix)?;
+                    self.push_double(ud as Double);
+                }
+                Builtin(NumberSignGreater) => {
+                    pop_double!("#>");
+                    self.stack.push(self.address(self.hold));
+                    self.stack.push((self.picture_end() - self.hold) as Cell);
+                }
+                Builtin(NumberSignS) => {
+                    let ud = pop_double!("#s");
+                    let radix = radix!("#s");
+                    self.hold_digits("#s", self.unsigned_double(ud), radix)?;
+                    self.push_double(0);
+                }
+                Builtin(Or) => apply!("or", |),
+                Builtin(Parse) => {
+                    let delimiter = pop!("parse");
+                    let delimiter = u32::try_from(delimiter)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or(Error::Static("parse: invalid delimiter"))?;
+                    let range = self.parse(input, |input| input.parse(delimiter));
+                    self.push_parsed(range);
+                }
+                Builtin(ParseName) => {
+                    let range = self.parse(input, |input| {
+                        input.skip(' ');
+                        input.parse(' ')
+                    });
+                    self.push_parsed(range);
+                }
+                Builtin(ParseCounted) => {
+                    let delimiter = pop!("word");
+                    let delimiter = u32::try_from(delimiter)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or(Error::Static("word: invalid delimiter"))?;
+                    let range = self.parse(input, |input| {
+                        input.skip(delimiter);
+                        input.parse(delimiter)
+                    });
+                    let text = &input.text[range];
+
+                    // The counted string is left at the end of the data space
+                    // without reserving it
+                    let len = u8::try_from(text.len())
+                        .map_err(|_| Error::Static("word: string too long"))?;
+                    let end = self.data_end();
+                    let buffer = self
+                        .memory
+                        .get_mut(self.here..end)
+                        .and_then(|buffer| buffer.get_mut(..=text.len()))
+                        .ok_or(Error::DataSpaceExhausted("word"))?;
+                    buffer[0] = len;
+                    buffer[1..].copy_from_slice(text.as_bytes());
+                    self.stack.push(self.address(self.here));
+                }
+                Builtin(Plus) => arithmetic!("plus", +),
+                Builtin(PlusStore) => {
+                    let addr = pop!("+!");
+                    let n = pop!("+!");
+                    let o = fetch!("+!", addr);
+                    store!("+!", addr, narrow!("+!", i128::from(o) + i128::from(n)));
+                }
+                Builtin(RFetch) => self.stack.push(rpeek!("r@", 0)),
+                Builtin(RFrom) => {
+                    let n = rpop!("r>");
+                    self.stack.push(n);
+                }
+                Builtin(RShift) => {
+                    let u = pop!("rshift");
+                    let n = pop!("rshift");
+                    let r = match u32::try_from(u) {
+                        Ok(u) if u < self.bits() => self.wrap((self.unsigned(n) >> u).into()),
+                        _ => 0,
+                    };
+                    self.stack.push(r);
+                }
+                Builtin(Refill) => {
+                    // The new line replaces the input buffer and is
+                    // interpreted once the current word finishes
+                    let line = self.read_line();
+                    if let Some(line) = &line {
+                        self.memory.truncate(self.data_end());
+                        self.memory.extend_from_slice(line.as_bytes());
+                        *input = Input::new(line);
+                        self.store(self.system(TO_IN), 0);
+                    }
+                    self.stack.push(-Cell::from(line.is_some()));
+                }
+                Builtin(Replaces) => {
+                    let len2 = pop!("replaces");
+                    let name = range!("replaces", pop!("replaces"), len2);
+                    let len1 = pop!("replaces");
+                    let text = range!("replaces", pop!("replaces"), len1);
+                    self.substitutions.insert(
+                        self.memory[name].to_ascii_lowercase(),
+                        self.memory[text].to_vec(),
+                    );
+                }
+                Builtin(ReturnStackPrint) => {
+                    let radix = radix!(".RS");
+                    output!("{} ", Self::format_stack(&self.return_stack, radix))
+                }
+                Builtin(RightBracket) => {
+                    if self.definition.is_none() {
+                        return Err(Error::Static("]: outside of a definition"));
+                    }
+                    self.store(self.system(STATE), -1);
+                }
+                Builtin(Rot) => {
+                    let n = pop!("rot", 2);
+                    self.stack.push(n);
+                }
+                Builtin(Sign) => {
+                    if pop!("sign") < 0 {
+                        self.hold("sign", b'-')?;
+                    }
+                }
+                Builtin(SearchWordlist) => {
+                    let wid = pop!("search-wordlist");
+                    let len = pop!("search-wordlist");
+                    let range = range!("search-wordlist", pop!("search-wordlist"), len);
+                    if wid != FORTH_WORDLIST {
+                        return Err(Error::Static("search-wordlist: invalid word list"));
+                    }
+                    match self.find_bytes(&self.memory[range]) {
+                        Some((xt, flag)) => {
+                            self.stack.push(xt as Cell);
+                            self.stack.push(flag);
+                        }
+                        None => self.stack.push(0),
+                    }
+                }
+                Builtin(Search) => {
+                    let len2 = pop!("search");
+                    let s2 = range!("search", pop!("search"), len2);
+                    let len1 = peek!("search");
+                    let addr1 = peek!("search", 1);
+                    let s1 = range!("search", addr1, len1);
+                    let (haystack, needle) = (&self.memory[s1], &self.memory[s2]);
+                    let found = match needle.len() {
+                        0 => Some(0),
+                        len => haystack.windows(len).position(|w| w == needle),
+                    };
+                    if let Some(i) = found {
+                        let len = self.stack.len();
+                        self.stack[len - 2] = self.wrap(i128::from(addr1) + i as i128);
+                        self.stack[len - 1] = len1 - i as Cell;
+                    }
+                    self.stack.push(-Cell::from(found.is_some()));
+                }
+                Builtin(Slash) => {
+                    let d = pop!("slash");
+                    let n = pop!("slash");
+                    let (_, q) = divide!("slash", n, d, self.config.division);
+                    self.stack.push(q);
+                }
+                Builtin(Source) => {
+                    self.stack.push(self.address(self.data_end()));
+                    self.stack.push(input.text.len() as Cell);
+                }
+                Builtin(SlashString) => {
+                    let n = pop!("/string");
+                    let len = pop!("/string");
+                    let addr = pop!("/string");
+                    self.stack.push(self.wrap(i128::from(addr) + i128::from(n)));
+                    self.stack.push(self.wrap(i128::from(len) - i128::from(n)));
+                }
+                Builtin(SlashMod) => {
+                    let d = pop!("slash-mod");
+                    let n = pop!("slash-mod");
+                    let (r, q) = divide!("slash-mod", n, d, self.config.division);
+                    self.stack.push(r);
+                    self.stack.push(q);
+                }
+                Builtin(StackPrint) => {
+                    let radix = radix!(".S");
+                    output!("{} ", Self::format_stack(&self.stack, radix))
+                }
+                Builtin(Spaces) => pad!(pop!("spaces").max(0) as u64),
+                Builtin(SmRem) => {
+                    let (r, q) = divide_double!("sm/rem", Division::Symmetric);
+                    self.stack.push(r);
+                    self.stack.push(q);
+                }
+                Builtin(Star) => arithmetic!("star", *),
+                Builtin(StarSlash) => {
+                    let (_, q) = scale!("star-slash");
+                    self.stack.push(q);
+                }
+                Builtin(StarSlashMod) => {
+                    let (r, q) = scale!("star-slash-mod");
+                    self.stack.push(r);
+                    self.stack.push(q);
+                }
+                Builtin(Store) => {
+                    let addr = pop!("!");
+                    let n = pop!("!");
+                    store!("!", addr, n);
+                }
+                Builtin(Substitute) => {
+                    let len2 = pop!("substitute");
+                    let addr2 = pop!("substitute");
+                    let buffer = range!("substitute", addr2, len2);
+                    let len1 = pop!("substitute");
+                    let text = range!("substitute", pop!("substitute"), len1);
+                    let (result, n) = self.substitute(&self.memory[text]);
+                    self.stack.push(addr2);
+                    if result.len() <= buffer.len() {
+                        self.memory[buffer.start..][..result.len()].copy_from_slice(&result);
+                        self.stack.push(result.len() as Cell);
+                        self.stack.push(n as Cell);
+                    } else {
+                        self.stack.push(0);
+                        self.stack.push(-1);
+                    }
+                }
+                Builtin(Swap) => {
+                    let n = pop!("swap", 1);
+                    self.stack.push(n);
+                }
+                Builtin(Tick) => {
+                    let name = self
+                        .parse(input, Input::parse_name)
+                        .ok_or(Error::NameMissing("'"))?;
+                    let xt = self.find(&name).ok_or(Error::UndefinedWord(name))?;
+                    self.stack.push(xt as Cell);
+                }
+                Builtin(ToBody) => {
+                    let xt = pop!(">body");
+                    let addr = self
+                        .xt(xt)
+                        .and_then(|xt| self.words[xt].body)
+                        .ok_or(Error::Static(">body: not a created word"))?;
+                    self.stack.push(addr);
+                }
+                Builtin(ToR) => {
+                    let n = pop!(">r");
+                    self.return_stack.push(n);
+                }
+                Builtin(TwoFetch) => {
+                    let addr = pop!("2@");
+                    let n2 = fetch!("2@", addr);
+                    let n1 = fetch!("2@", addr.wrapping_add(self.cell_size() as Cell));
+                    self.stack.push(n1);
+                    self.stack.push(n2);
+                }
+                Builtin(TwoOver) => {
+                    let n1 = peek!("2over", 3);
+                    let n2 = peek!("2over", 2);
+                    self.stack.push(n1);
+                    self.stack.push(n2);
+                }
+                Builtin(TwoRFetch) => {
+                    let n1 = rpeek!("2r@", 1);
+                    let n2 = rpeek!("2r@", 0);
+                    self.stack.push(n1);
+                    self.stack.push(n2);
+                }
+                Builtin(TwoRFrom) => {
+                    let n2 = rpop!("2r>");
+                    let n1 = rpop!("2r>");
+                    self.stack.push(n1);
+                    self.stack.push(n2);
+                }
+                Builtin(TwoToR) => {
+                    let n2 = pop!("2>r");
+                    let n1 = pop!("2>r");
+                    self.return_stack.push(n1);
+                    self.return_stack.push(n2);
+                }
+                Builtin(TwoSlash) => {
+                    let n = pop!("2/");
+                    self.stack.push(n >> 1);
+                }
+                Builtin(TwoStar) => {
+                    let n = pop!("2*");
+                    let r = narrow!("2*", i128::from(n) * 2);
+                    self.stack.push(r);
+                }
+                Builtin(TwoStore) => {
+                    let addr = pop!("2!");
+                    let n2 = pop!("2!");
+                    let n1 = pop!("2!");
+                    store!("2!", addr, n2);
+                    store!("2!", addr.wrapping_add(self.cell_size() as Cell), n1);
+                }
+                Builtin(TwoSwap) => {
+                    let n1 = pop!("2swap", 3);
+                    let n2 = pop!("2swap", 2);
+                    self.stack.push(n1);
+                    self.stack.push(n2);
+                }
+                Builtin(Type) => {
+                    let len = pop!("type");
+                    let addr = pop!("type");
+                    let range = range!("type", addr, len);
+                    output!("{}", String::from_utf8_lossy(&self.memory[range]));
+                }
+                Builtin(UDot) => {
+                    let u = pop!("u.");
+                    let s = self.picture("u.", self.unsigned(u).into(), false, radix!("u."))?;
+                    output!("{s} ")
+                }
+                Builtin(UDotR) => {
+                    let width = pop!("u.r").max(0) as u64;
+                    let u = pop!("u.r");
+                    let s = self.picture("u.r", self.unsigned(u).into(), false, radix!("u.r"))?;
+                    pad!(width.saturating_sub(s.len() as u64));
+                    output!("{s}");
+                }
+                Builtin(ULess) => {
+                    let u2 = pop!("u-less-than");
+                    let u1 = pop!("u-less-than");
+                    let flag = self.unsigned(u1) < self.unsigned(u2);
+                    self.stack.push(-Cell::from(flag));
+                }
+                Builtin(UMSlashMod) => {
+                    let u = pop!("um/mod");
+                    let ud = pop_double!("um/mod");
+                    let (u, ud) = (u128::from(self.unsigned(u)), self.unsigned_double(ud));
+                    if u == 0 {
+                        return Err(Error::DivisionByZero("um/mod"));
+                    }
+                    let q = ud / u;
+                    let fits = q >> self.bits() == 0;
+                    let q = overflow!("um/mod", fits.then_some(q), q);
+                    self.stack.push(self.wrap((ud % u) as i128));
+                    self.stack.push(self.wrap(q as i128));
+                }
+                Builtin(UMStar) => {
+                    let u2 = pop!("um*");
+                    let u1 = pop!("um*");
+                    let ud = u128::from(self.unsigned(u1)) * u128::from(self.unsigned(u2));
+                    self.push_double(ud as i128);
+                }
+                Builtin(Unescape) => {
+                    let addr2 = pop!("unescape");
+                    let len1 = pop!("unescape");
+                    let text = range!("unescape", pop!("unescape"), len1);
+                    let mut result = Vec::new();
+                    for &b in &self.memory[text] {
+                        result.push(b);
+                        if b == b'%' {
+                            result.push(b'%');
+                        }
+                    }
+                    let buffer = range!("unescape", addr2, result.len() as Cell);
+                    self.memory[buffer].copy_from_slice(&result);
+                    self.stack.push(addr2);
+                    self.stack.push(result.len() as Cell);
+                }
+                Builtin(Unloop) => {
+                    rpop!("unloop");
+                    rpop!("unloop");
+                }
+                Builtin(Within) => {
+                    let hi = pop!("within");
+                    let lo = pop!("within");
+                    let n = pop!("within");
+                    let flag =
+                        self.unsigned(n.wrapping_sub(lo)) < self.unsigned(hi.wrapping_sub(lo));
+                    self.stack.push(-Cell::from(flag));
+                }
+                Builtin(Xor) => apply!("xor", ^),
+                #[cfg(feature = "float")]
+                Builtin(DToF) => {
+                    let d = pop_double!("d>f");
+                    self.float_stack.push(d as f64);
+                }
+                #[cfg(feature = "float")]
+                Builtin(FDot) => output!("{} ", fpop!("f.")),
+                #[cfg(feature = "float")]
+                Builtin(FDrop) => {
+                    fpop!("fdrop");
+                }
+                #[cfg(feature = "float")]
+                Builtin(FDup) => {
+                    let r = fpop!("fdup");
+                    self.float_stack.extend([r, r]);
+                }
+                #[cfg(feature = "float")]
+                Builtin(FExp) => funary!("fexp", exp),
+                #[cfg(feature = "float")]
+                Builtin(FFetch) => {
+                    let addr = pop!("f@");
+                    let bytes = *self.bytes(addr).ok_or(Error::AddressInvalid("f@", addr))?;
+                    self.float_stack.push(f64::from_le_bytes(bytes));
+                }
+                #[cfg(feature = "float")]
+                Builtin(FLess) => {
+                    let r2 = fpop!("f<");
+                    let r1 = fpop!("f<");
+                    self.stack.push(-Cell::from(r1 < r2));
+                }
+                #[cfg(feature = "float")]
+                Builtin(FLn) => funary!("fln", ln),
+                #[cfg(feature = "float")]
+                Builtin(FMinus) => fapply!("f-", -),
+                #[cfg(feature = "float")]
+                Builtin(FOver) => {
+                    let r2 = fpop!("fover");
+                    let r1 = fpop!("fover");
+                    self.float_stack.extend([r1, r2, r1]);
+                }
+                #[cfg(feature = "float")]
+                Builtin(FPlus) => fapply!("f+", +),
+                #[cfg(feature = "float")]
+                Builtin(FSin) => funary!("fsin", sin),
+                #[cfg(feature = "float")]
+                Builtin(FSlash) => fapply!("f/", /),
+                #[cfg(feature = "float")]
+                Builtin(FSqrt) => funary!("fsqrt", sqrt),
+                #[cfg(feature = "float")]
+                Builtin(FStar) => fapply!("f*", *),
+                #[cfg(feature = "float")]
+                Builtin(FStore) => {
+                    let addr = pop!("f!");
+                    let r = fpop!("f!");
+                    let bytes = self.bytes(addr).ok_or(Error::AddressInvalid("f!", addr))?;
+                    *bytes = r.to_le_bytes();
+                }
+                #[cfg(feature = "float")]
+                Builtin(FSwap) => {
+                    let r2 = fpop!("fswap");
+                    let r1 = fpop!("fswap");
+                    self.float_stack.extend([r2, r1]);
+                }
+                #[cfg(feature = "float")]
+                Builtin(FToD) => {
+                    let r = fpop!("f>d");
+                    let bound = 2f64.powi(2 * self.bits() as i32 - 1);
+                    let d = (-bound..bound).contains(&r.trunc()).then_some(r as i128);
+                    let d = narrow_double!("f>d", d, r as i128);
+                    self.push_double(d);
+                }
+                #[cfg(feature = "float")]
+                Builtin(FZeroEquals) => {
+                    let r = fpop!("f0=");
+                    self.stack.push(-Cell::from(r == 0.0));
+                }
+                Call(xt) => call!(xt, 0),
+                CallAt(xt, at) => call!(xt, at),
+                Token::Directive(directive) => self.direct(directive, input, out)?,
+                Define(defining) => {
+                    let name = self
+                        .parse(input, Input::parse_name)
+                        .ok_or(Error::NameMissing(match defining {
+                            Defining::Constant => "constant",
+                            Defining::Create => "create",
+                            #[cfg(feature = "float")]
+                            Defining::FConstant => "fconstant",
+                            #[cfg(feature = "float")]
+                            Defining::FVariable => "fvariable",
+                            Defining::TwoConstant => "2constant",
+                            Defining::TwoVariable => "2variable",
+                            Defining::Marker => "marker",
+                            Defining::Value => "value",
+                            Defining::Variable => "variable",
+                        }))?;
+                    let mut body = None;
+                    let tokens = match defining {
+                        Defining::Constant => vec![Number(pop!("constant"))],
+                        Defining::Create => {
+                            self.here = self.aligned(self.here);
+                            body = Some(self.address(self.here));
+                            vec![Number(self.address(self.here))]
+                        }
+                        #[cfg(feature = "float")]
+                        Defining::FConstant => vec![FNumber(fpop!("fconstant"))],
+                        #[cfg(feature = "float")]
+                        Defining::FVariable => {
+                            self.here = self.aligned(self.here);
+                            let addr = self.allot("fvariable", size_of::<f64>() as isize)?;
+                            let addr = self.address(addr);
+                            *self
+                                .bytes(addr)
+                                .ok_or(Error::AddressInvalid("fvariable", addr))? =
+                                0f64.to_le_bytes();
+                            body = Some(addr);
+                            vec![Number(addr)]
+                        }
+                        Defining::TwoConstant => {
+                            let n2 = pop!("2constant");
+                            let n1 = pop!("2constant");
+                            vec![Number(n1), Number(n2)]
+                        }
+                        Defining::TwoVariable => {
+                            let addr = self.allot_cell("2variable")?;
+                            self.allot("2variable", self.cell_size() as isize)?;
+                            store!("2variable", addr, 0);
+                            store!("2variable", addr.wrapping_add(self.cell_size() as Cell), 0);
+                            body = Some(addr);
+                            vec![Number(addr)]
+                        }
+                        Defining::Marker => {
+                            self.mark(name);
+                            continue;
+                        }
+                        Defining::Value => {
+                            let n = pop!("value");
+                            let addr = self.allot_cell("value")?;
+                            store!("value", addr, n);
+                            body = Some(addr);
+                            vec![Value(addr)]
+                        }
+                        Defining::Variable => {
+                            let addr = self.allot_cell("variable")?;
+                            store!("variable", addr, 0);
+                            body = Some(addr);
+                            vec![Number(addr)]
+                        }
+                    };
+                    let xt = self.define(name, tokens);
+                    self.words[xt].body = body;
+                    if let Defining::Create = defining {
+                        self.created = Some(xt);
+                    }
+                }
+                Does => {
+                    let (Some(xt), Some(created)) = (current, self.created) else {
+                        return Err(Error::Static("does>: no word created"));
+                    };
+
+                    // Have the created word push its data field and then run
+                    // the rest of this definition, which returns immediately
+                    let body = &mut self.words[created].tokens;
+                    body.truncate(1);
+                    body.push(CallAt(xt, pc));
+                    pc = self.words[xt].tokens.len();
+                }
+                Do => {
+                    let index = pop!("do");
+                    let limit = pop!("do");
+                    self.return_stack.push(limit);
+                    self.return_stack.push(index);
+                }
+                QuestionDo(offset) => {
+                    let index = pop!("?do");
+                    let limit = pop!("?do");
+                    if index == limit {
+                        pc = pc.wrapping_add_signed(offset);
+                    } else {
+                        self.return_stack.push(limit);
+                        self.return_stack.push(index);
+                    }
+                }
+                Leave(offset) => {
+                    rpop!("leave");
+                    rpop!("leave");
+                    pc = pc.wrapping_add_signed(offset);
+                }
+                Loop(offset) => {
+                    if self
+                        .step_loop(1)
+                        .ok_or(Error::Static("loop: return stack underflow"))?
+                    {
+                        pc = pc.wrapping_add_signed(offset);
+                    }
+                }
+                PlusLoop(offset) => {
+                    let n = pop!("+loop");
+                    if self
+                        .step_loop(n)
+                        .ok_or(Error::Static("+loop: return stack underflow"))?
+                    {
+                        pc = pc.wrapping_add_signed(offset);
+                    }
+                }
+                Exit => match frames.pop() {
+                    Some((xt, ret)) => {
+                        current = xt;
+                        pc = ret;
+                    }
+                    None => break,
+                },
+                Jump(offset) => pc = pc.wrapping_add_signed(offset),
+                JumpIfZero(offset) => {
+                    if pop!("if") == 0 {
+                        pc = pc.wrapping_add_signed(offset)
+                    }
+                }
+                Marker(ref marker, here) => {
+                    // Walk back every definition until the marker
+                    // is found or the history is exhausted
+                    for defs in self.dictionary.values_mut() {
+                        while let Some(xt) = defs.pop() {
+                            match self.words[xt].tokens.first() {
+                                Some(Marker(m, _)) if m == marker => break,
+                                _ => {}
+                            }
+                        }
+                    }
+                    self.here = here;
+                }
+                #[cfg(feature = "float")]
+                FNumber(r) => self.float_stack.push(r),
+                Number(n) => self.stack.push(n),
+                Value(addr) => {
+                    let n = fetch!("value", addr);
+                    self.stack.push(n);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replace each `%name%` in `text` that names a substitution defined by
+    /// `replaces` with its text, and each `%%` with `%`. Returns the result
+    /// and the number of substitutions made.
+    fn substitute(&self, mut text: &[u8]) -> (Vec<u8>, usize) {
+        let mut result = Vec::new();
+        let mut n = 0;
+        while let Some(start) = text.iter().position(|&b| b == b'%') {
+            result.extend_from_slice(&text[..start]);
+            text = &text[start + 1..];
+            let Some(end) = text.iter().position(|&b| b == b'%') else {
+                result.push(b'%');
+                break;
+            };
+
+            let name = text[..end].to_ascii_lowercase();
+            if name.is_empty() {
+                result.push(b'%');
+            } else if let Some(substitution) = self.substitutions.get(&name) {
+                result.extend_from_slice(substitution);
+                n += 1;
+            } else {
+                // Leave unknown names as they are
+                result.push(b'%');
+                result.extend_from_slice(&text[..=end]);
+            }
+            text = &text[end + 1..];
+        }
+        result.extend_from_slice(text);
+        (result, n)
+    }
+
+    /// Push a double-cell number, low cell first, truncating it to fit.
+    fn push_double(&mut self, d: Double) {
+        self.stack.push(self.wrap(d));
+        self.stack.push(self.wrap(d >> self.bits()));
+    }
+
+    fn format_stack(stack: &[Cell], radix: u32) -> String {
+        format!(
+            "<{}> {}",
+            stack.len(),
+            stack
+                .iter()
+                .map(|&n| format_integer(n.into(), radix))
+                .collect::<Vec<String>>()
+                .join(" ")
+        )
+    }
+
+    /// Advance the innermost loop index by `n`, returning whether the loop
+    /// should continue. The loop ends once the index crosses the boundary
+    /// between the limit minus one and the limit, in either direction.
+    /// Returns `None` if there are no loop parameters.
+    fn step_loop(&mut self, n: Cell) -> Option<bool> {
+        let [.., limit, index] = self.return_stack[..] else {
+            return None;
+        };
+
+        let distance = self.unsigned(index.wrapping_sub(limit));
+        let crossed = if n < 0 {
+            distance < n.unsigned_abs()
+        } else {
+            (u128::from(distance) + n as u128) >> self.bits() != 0
+        };
+
+        let len = self.return_stack.len();
+        if crossed {
+            self.return_stack.truncate(len - 2);
+        } else {
+            self.return_stack[len - 1] = self.wrap(i128::from(index) + i128::from(n));
+        }
+        Some(!crossed)
+    }
+
+    /// Compile `word`, or execute it now if it is immediate.
+    fn compile<'a>(
+        &mut self,
+        word: &str,
+        input: &mut Input,
+        out: &mut dyn Write,
+    ) -> Result<(), Error<'a>> {
+        let (radix, bits) = (self.radix(), self.bits());
+        let recursive_names = self.config.recursive_names;
+        let recursive = match &self.compiler().name {
+            Some((name, xt)) if recursive_names && name == word => Some(*xt),
+            _ => None,
+        };
+
+        let mut tokens = Vec::new();
+        if let Some(xt) = recursive {
+            tokens.push(Token::Call(xt));
+        } else if let Some(xt) = self.find(word) {
+            // Words take precedence over numbers, which in a large enough
+            // base may be spelled the same
+            if self.words[xt].immediate {
+                return self.run(&[Token::Call(xt)], input, out);
+            }
+            tokens.push(Token::Call(xt));
+        } else if let Some(c) = parse_char(word) {
+            tokens.push(Token::Number(self.wrap(u32::from(c).into())));
+        } else if let Some(n) = parse_integer(word, radix, bits) {
+            tokens.push(Token::Number(self.wrap(n)));
+        } else if let Some(d) = word
+            .strip_suffix('.')
+            .and_then(|word| parse_integer(word, radix, 2 * bits))
+        {
+            // A trailing point marks a double-cell number
+            tokens.push(Token::Number(self.wrap(d)));
+            tokens.push(Token::Number(self.wrap(d >> bits)));
+        } else if let Some(token) = parse_float(word) {
+            tokens.push(token);
+        } else {
+            return Err(Error::UndefinedWord(word.into()));
+        }
+        self.compiler().tokens.extend(tokens);
+        Ok(())
+    }
+
+    /// Carry out the compile-time behavior of `directive`, parsing any
+    /// further input it needs.
+    fn direct<'a>(
+        &mut self,
+        directive: Directive,
+        input: &mut Input,
+        out: &mut dyn Write,
+    ) -> Result<(), Error<'a>> {
+        use Directive::*;
+
+        match directive {
+            Paren => {
+                let range = self.parse(input, |input| input.parse(')'));
+                // A comment in a definition may continue on the next line
+                self.commenting = self.defining() && range.end == input.pos;
+            }
+            CloseParen => return Err(Error::Static("unbalanced closing comment")),
+            DotParen => {
+                let range = self.parse(input, |input| input.parse(')'));
+                out.write_all(input.text[range].as_bytes())
+                    .and_then(|()| out.flush())
+                    .map_err(Error::Output)?;
+            }
+            DotQuote | SQuote => {
+                let range = self.parse(input, |input| input.parse('"'));
+                let text = &input.text[range];
+                let op = match directive {
+                    DotQuote => ".\"",
+                    _ => "s\"",
+                };
+                let addr = self.string_bytes(op, text.as_bytes())?;
+                let tokens = &mut self.compiler().tokens;
+                tokens.push(Token::Number(addr));
+                tokens.push(Token::Number(text.len() as Cell));
+                if let DotQuote = directive {
+                    tokens.push(Token::Builtin(Word::Type));
+                }
+            }
+            SBackslashQuote => {
+                let bytes = self
+                    .parse(input, Input::parse_escaped)
+                    .ok_or(Error::Static("s\\\": invalid escape"))?;
+                let addr = self.string_bytes("s\\\"", &bytes)?;
+                let tokens = &mut self.compiler().tokens;
+                tokens.push(Token::Number(addr));
+                tokens.push(Token::Number(bytes.len() as Cell));
+            }
+            SLiteral => {
+                let (Some(len), Some(addr)) = (self.stack.pop(), self.stack.pop()) else {
+                    return Err(Error::Static("sliteral: stack underflow"));
+                };
+                let range = self
+                    .range(addr, len)
+                    .ok_or(Error::AddressInvalid("sliteral", addr))?;
+                let bytes = self.memory[range].to_vec();
+                let addr = self.string_bytes("sliteral", &bytes)?;
+                let tokens = &mut self.compiler().tokens;
+                tokens.push(Token::Number(addr));
+                tokens.push(Token::Number(len));
+            }
+            CQuote => {
+                let range = self.parse(input, |input| input.parse('"'));
+                let text = &input.text[range];
+                let len =
+                    u8::try_from(text.len()).map_err(|_| Error::Static("c\": string too long"))?;
+                let addr = self.string_bytes("c\"", &[&[len], text.as_bytes()].concat())?;
+                self.compiler().tokens.push(Token::Number(addr));
+            }
+            Literal => {
+                let n = self
+                    .stack
+                    .pop()
+                    .ok_or(Error::Static("literal: stack underflow"))?;
+                self.compiler().tokens.push(Token::Number(n));
+            }
+            BracketChar => {
+                let name = self
+                    .parse(input, Input::parse_name)
+                    .ok_or(Error::NameMissing("[char]"))?;
+                let c = name.chars().next().unwrap_or_default();
+                self.compiler()
+                    .tokens
+                    .push(Token::Number(u32::from(c).into()));
+            }
+            BracketTick => {
+                let name = self
+                    .parse(input, Input::parse_name)
+                    .ok_or(Error::NameMissing("[']"))?;
+                let xt = self.find(&name).ok_or(Error::UndefinedWord(name))?;
+                self.compiler().tokens.push(Token::Number(xt as Cell));
+            }
+            Postpone => {
+                let name = self
+                    .parse(input, Input::parse_name)
+                    .ok_or(Error::NameMissing("postpone"))?;
+                let xt = self.find(&name).ok_or(Error::UndefinedWord(name))?;
+                let immediate = self.words[xt].immediate;
+                let tokens = &mut self.compiler().tokens;
+                if immediate {
+                    // Have the immediate word run when the definition does
+                    tokens.push(Token::Call(xt));
+                } else {
+                    tokens.push(Token::Number(xt as Cell));
+                    tokens.push(Token::Builtin(Word::CompileComma));
+                }
+            }
+            LeftBracket => {
+                self.store(self.system(STATE), 0);
+            }
+            Semicolon => self.end_definition()?,
+            If => {
+                let Compiler {
+                    tokens, control, ..
+                } = self.compiler();
+                control.push(Control::Orig(tokens.len()));
+                tokens.push(Token::JumpIfZero(0));
+            }
+            Else => {
+                let Compiler {
+                    tokens, control, ..
+                } = self.compiler();
+                let Some(Control::Orig(orig)) = control.pop() else {
+                    return Err(Error::Static("else: unbalanced"));
+                };
+                control.push(Control::Orig(tokens.len()));
+                tokens.push(Token::Jump(0));
+                Self::resolve(tokens, orig);
+            }
+            Then => {
+                let Compiler {
+                    tokens, control, ..
+                } = self.compiler();
+                let Some(Control::Orig(orig)) = control.pop() else {
+                    return Err(Error::Static("then: unbalanced"));
+                };
+                Self::resolve(tokens, orig);
+            }
+            Do => {
+                let Compiler {
+                    tokens, control, ..
+                } = self.compiler();
+                tokens.push(Token::Do);
+                control.push(Control::Do(tokens.len(), Vec::new()));
+            }
+            QuestionDo => {
+                let Compiler {
+                    tokens, control, ..
+                } = self.compiler();
+                tokens.push(Token::QuestionDo(0));
+                control.push(Control::Do(tokens.len(), vec![tokens.len() - 1]));
+            }
+            Leave => {
+                let Compiler {
+                    tokens, control, ..
+                } = self.compiler();
+                let Some(leaves) = control.iter_mut().rev().find_map(|c| match c {
+                    Control::Do(_, leaves) => Some(leaves),
+                    _ => None,
+                }) else {
+                    return Err(Error::Static("leave: outside of a loop"));
+                };
+                leaves.push(tokens.len());
+                tokens.push(Token::Leave(0));
+            }
+            Loop | PlusLoop => {
+                let Compiler {
+                    tokens, control, ..
+                } = self.compiler();
+                let Some(Control::Do(dest, leaves)) = control.pop() else {
+                    return Err(Error::Static("loop: unbalanced"));
+                };
+                let offset = Self::offset_to(tokens, dest);
+                tokens.push(match directive {
+                    Loop => Token::Loop(offset),
+                    _ => Token::PlusLoop(offset),
+                });
+                for orig in leaves {
+                    Self::resolve(tokens, orig);
+                }
+            }
+            Begin => {
+                let Compiler {
+                    tokens, control, ..
+                } = self.compiler();
+                control.push(Control::Dest(tokens.len()));
+            }
+            Recurse => {
+                let Compiler { name, tokens, .. } = self.compiler();
+                let Some((_, xt)) = name else {
+                    return Err(Error::Static("recurse: outside of a definition"));
+                };
+                tokens.push(Token::Call(*xt));
+            }
+            Exit => self.compiler().tokens.push(Token::Exit),
+            Does => {
+                let Compiler { name, tokens, .. } = self.compiler();
+                if name.is_none() {
+                    return Err(Error::Static("does>: outside of a definition"));
+                }
+                tokens.push(Token::Does);
+            }
+            To => {
+                let name = self
+                    .parse(input, Input::parse_name)
+                    .ok_or(Error::NameMissing("to"))?;
+                let xt = self.find(&name).ok_or(Error::UndefinedWord(name))?;
+                let [Token::Value(addr)] = self.words[xt].tokens[..] else {
+                    return Err(Error::Static("to: not a value"));
+                };
+                let tokens = &mut self.compiler().tokens;
+                tokens.push(Token::Number(addr));
+                tokens.push(Token::Builtin(Word::Store));
+            }
+            Until | Again => {
+                let Compiler {
+                    tokens, control, ..
+                } = self.compiler();
+                let Some(Control::Dest(dest)) = control.pop() else {
+                    return Err(Error::Static("begin: unbalanced"));
+                };
+                let offset = Self::offset_to(tokens, dest);
+                tokens.push(match directive {
+                    Until => Token::JumpIfZero(offset),
+                    _ => Token::Jump(offset),
+                });
+            }
+            While => {
+                let Compiler {
+                    tokens, control, ..
+                } = self.compiler();
+                let Some(Control::Dest(dest)) = control.pop() else {
+                    return Err(Error::Static("while: unbalanced"));
+                };
+                control.push(Control::Orig(tokens.len()));
+                control.push(Control::Dest(dest));
+                tokens.push(Token::JumpIfZero(0));
+            }
+            Repeat => {
+                let Compiler {
+                    tokens, control, ..
+                } = self.compiler();
+                let (Some(Control::Dest(dest)), Some(Control::Orig(orig))) =
+                    (control.pop(), control.pop())
+                else {
+                    return Err(Error::Static("repeat: unbalanced"));
+                };
+                tokens.push(Token::Jump(Self::offset_to(tokens, dest)));
+                Self::resolve(tokens, orig);
+            }
+        }
+        Ok(())
+    }
+
+    /// The execution token of the latest definition of `word`.
+    fn find(&self, word: &str) -> Option<usize> {
+        // Find the latest definition, skipping markers
+        self.dictionary.get(word).and_then(|defs| {
+            defs.iter().rev().copied().find(
+                |&xt| !matches!(self.words[xt].tokens.first(), Some(Token::Marker(m, _)) if m != word),
+            )
+        })
+    }
+
+    /// The execution token of the latest definition of the word named by
+    /// `bytes`, along with 1 if it is immediate or -1 if not.
+    fn find_bytes(&self, bytes: &[u8]) -> Option<(usize, Cell)> {
+        let xt = self.find(&String::from_utf8_lossy(bytes))?;
+        Some((xt, if self.words[xt].immediate { 1 } else { -1 }))
+    }
+
+    /// The execution token `n`, if it identifies a definition.
+    fn xt(&self, n: Cell) -> Option<usize> {
+        usize::try_from(n).ok().filter(|&xt| xt < self.words.len())
+    }
+
+    /// The offset for a backward jump, appended to `tokens`, to `dest`.
+    fn offset_to(tokens: &[Token], dest: usize) -> isize {
+        dest as isize - tokens.len() as isize - 1
+    }
+
+    /// Point the forward jump at `orig` to the end of `tokens`.
+    fn resolve(tokens: &mut [Token], orig: usize) {
+        let offset = (tokens.len() - orig - 1) as isize;
+        match &mut tokens[orig] {
+            Token::Jump(o) | Token::JumpIfZero(o) | Token::Leave(o) | Token::QuestionDo(o) => {
+                *o = offset
+            }
+            _ => unreachable!("resolving a non-jump token"),
+        }
+    }
+}
+
+pub enum Error<'a> {
+    AddressInvalid(&'a str, Cell),
+    ArithmeticOverflow(&'a str),
+    DataSpaceExhausted(&'a str),
+    DivisionByZero(&'a str),
+    NameMissing(&'a str),
+    Output(io::Error),
+    PictureOverflow(&'a str),
+    Static(&'a str),
+    UndefinedWord(String),
+    UnicodeInvalid(u32),
+}
+
+impl<'a> fmt::Display for Error<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use Error::*;
+
+        match *self {
+            AddressInvalid(op, addr) => write!(f, "{op}: invalid address {addr}"),
+            ArithmeticOverflow(op) => write!(f, "{op}: arithmetic overflow"),
+            DataSpaceExhausted(op) => write!(f, "{op}: data space exhausted"),
+            DivisionByZero(op) => write!(f, "{op}: division by zero"),
+            NameMissing(op) => write!(f, "{op}: no name specified"),
+            Output(ref err) => write!(f, "error writing output ({err})"),
+            PictureOverflow(op) => write!(f, "{op}: pictured numeric output overflow"),
+            Static(err) => f.write_str(err),
+            UndefinedWord(ref w) => write!(f, "undefined word '{w}'"),
+            UnicodeInvalid(v) => write!(f, "emit: invalid unicode {v:#04x}"),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum Token {
+    Builtin(Word),
+    /// Execute the definition with the given execution token.
+    Call(usize),
+    /// Like `Call`, but starting from the given index within the definition.
+    CallAt(usize, usize),
+    /// Define a new word, parsing its name from the input.
+    Define(Defining),
+    /// Carry out the compile-time behavior of a word such as `if`.
+    Directive(Directive),
+    /// Move the limit and index from the stack to the return stack.
+    Do,
+    /// Give the most recently created word the run-time behavior that
+    /// follows, then return.
+    Does,
+    /// Return from the definition being executed.
+    Exit,
+    /// Unconditionally move the program counter by the offset, relative to
+    /// the following token.
+    Jump(isize),
+    /// Pop the top of the stack and jump like `Jump` if it was zero.
+    JumpIfZero(isize),
+    /// Discard the loop parameters and jump like `Jump` out of the loop.
+    Leave(isize),
+    /// Increment the loop index and jump like `Jump` back to the start of the
+    /// loop body unless the limit was reached.
+    Loop(isize),
+    #[cfg(feature = "float")]
+    FNumber(f64),
+    /// Remove every definition made since the named marker, and release the
+    /// data space above the given address.
+    Marker(String, usize),
+    Number(Cell),
+    /// Like `Loop`, but increments the index by the value popped from the
+    /// stack.
+    PlusLoop(isize),
+    /// Like `Do`, but jumps like `Jump` past the loop if the limit and index
+    /// are equal.
+    QuestionDo(isize),
+    /// Push the contents of the given data space cell.
+    Value(Cell),
+}
+
+/// The kinds of words created at run time by defining words.
+#[derive(Clone, Copy)]
+enum Defining {
+    Constant,
+    Create,
+    #[cfg(feature = "float")]
+    FConstant,
+    #[cfg(feature = "float")]
+    FVariable,
+    Marker,
+    TwoConstant,
+    TwoVariable,
+    Value,
+    Variable,
+}
+
+/// The words which compile control structures and literals, and are
+/// therefore immediate.
+#[derive(Clone, Copy)]
+enum Directive {
+    Again,
+    Begin,
+    BracketChar,
+    BracketTick,
+    CloseParen,
+    CQuote,
+    Do,
+    Does,
+    DotParen,
+    DotQuote,
+    Else,
+    Exit,
+    If,
+    Leave,
+    LeftBracket,
+    Literal,
+    Loop,
+    Paren,
+    PlusLoop,
+    Postpone,
+    QuestionDo,
+    Recurse,
+    Repeat,
+    SBackslashQuote,
+    Semicolon,
+    SLiteral,
+    SQuote,
+    Then,
+    To,
+    Until,
+    While,
+}
+
+/// The text being interpreted and how much of it has been parsed.
+struct Input {
+    text: String,
+    pos: usize,
+}
+
+impl Input {
+    fn new(text: &str) -> Self {
+        Self {
+            text: text.into(),
+            pos: 0,
+        }
+    }
+
+    /// Parse the next whitespace-delimited word, if there is one.
+    fn parse_name(&mut self) -> Option<String> {
+        self.skip(' ');
+        let name = self.parse(' ');
+        (!name.is_empty()).then(|| self.text[name].into())
+    }
+
+    /// Parse up to the next `delimiter` (or the end of the input), consuming
+    /// the delimiter but not including it, and return where the parsed text
+    /// lies in the input. A space delimiter matches any whitespace.
+    fn parse(&mut self, delimiter: char) -> Range<usize> {
+        let start = self.pos;
+        let rest = &self.text[start..];
+        let len = rest.find(|c| delimits(c, delimiter)).unwrap_or(rest.len());
+        self.pos += rest[len..]
+            .chars()
+            .next()
+            .map_or(len, |c| len + c.len_utf8());
+        start..start + len
+    }
+
+    /// Skip any leading `delimiter`s, as matched by `parse`.
+    fn skip(&mut self, delimiter: char) {
+        let rest = &self.text[self.pos..];
+        self.pos = self.text.len() - rest.trim_start_matches(|c| delimits(c, delimiter)).len();
+    }
+
+    /// Parse up to the next unescaped `"`, translating the escapes recognized
+    /// by `s\"`. Returns `None` if an escape is invalid.
+    fn parse_escaped(&mut self) -> Option<Vec<u8>> {
+        let mut rest = self.text.as_bytes()[self.pos..].iter();
+        let mut bytes = Vec::new();
+        while let Some(&b) = rest.next() {
+            self.pos += 1;
+            match b {
+                b'"' => break,
+                b'\\' => {
+                    self.pos += 1;
+                    match rest.next()? {
+                        b'a' => bytes.push(7),
+                        b'b' => bytes.push(8),
+                        b'e' => bytes.push(27),
+                        b'f' => bytes.push(12),
+                        b'l' | b'n' => bytes.push(b'\n'),
+                        b'm' => bytes.extend(b"\r\n"),
+                        b'q' | b'"' => bytes.push(b'"'),
+                        b'r' => bytes.push(b'\r'),
+                        b't' => bytes.push(b'\t'),
+                        b'v' => bytes.push(11),
+                        b'z' => bytes.push(0),
+                        b'\\' => bytes.push(b'\\'),
+                        b'x' => {
+                            self.pos += 2;
+                            let mut digit = || char::from(*rest.next()?).to_digit(16);
+                            bytes.push((digit()? * 16 + digit()?) as u8);
+                        }
+                        _ => return None,
+                    }
+                }
+                _ => bytes.push(b),
+            }
+        }
+        Some(bytes)
+    }
+
+    /// Move the parse position to `pos`, or as near to it as possible.
+    fn seek(&mut self, pos: usize) {
+        let mut pos = pos.min(self.text.len());
+        while !self.text.is_char_boundary(pos) {
+            pos += 1;
+        }
+        self.pos = pos;
+    }
+}
+
+/// Whether `c` matches `delimiter`, where a space matches any whitespace.
+fn delimits(c: char, delimiter: char) -> bool {
+    match delimiter {
+        ' ' => c.is_ascii_whitespace(),
+        _ => c == delimiter,
+    }
+}
+
+/// A word's compiled definition, identified by its execution token.
+struct Definition {
+    tokens: Tokens,
+    /// Whether the word is executed even while compiling, rather than
+    /// compiled, as marked by `immediate`.
+    immediate: bool,
+    /// The address of the data field of a word defined by `create` or one
+    /// of the variable defining words, for `>body`.
+    body: Option<Cell>,
+}
+
+/// The state of a definition, or top-level phrase, being compiled.
+#[derive(Default)]
+struct Compiler {
+    /// The name of the word being defined, if any, and the execution token
+    /// reserved for it.
+    name: Option<(String, usize)>,
+    tokens: Tokens,
+    control: Vec<Control>,
+}
+
+impl Compiler {
+    /// The compiled tokens, provided every control structure was closed.
+    fn finish<'a>(self) -> Result<Tokens, Error<'a>> {
+        match self.control.last() {
+            Some(Control::Orig(_)) => Err(Error::Static("if: unbalanced")),
+            Some(Control::Do(..)) => Err(Error::Static("do: unbalanced")),
+            Some(Control::Dest(_)) => Err(Error::Static("begin: unbalanced")),
+            None => Ok(self.tokens),
+        }
+    }
+}
+
+/// Unresolved control-flow references, tracked while compiling.
+enum Control {
+    /// The index of a forward jump awaiting its destination.
+    Orig(usize),
+    /// The start of a counted loop body and the indices of the jumps out of
+    /// the loop.
+    Do(usize, Vec<usize>),
+    /// The destination of a backward jump.
+    Dest(usize),
+}
+
+#[derive(Clone, Copy)]
+enum Word {
+    Abs,
+    Accept,
+    Align,
+    Aligned,
+    Allot,
+    And,
+    CComma,
+    CFetch,
+    CMove,
+    CMoveUp,
+    Colon,
+    Comma,
+    Compare,
+    CompileComma,
+    Count,
+    CStore,
+    DAbs,
+    DashTrailing,
+    DDot,
+    DDotR,
+    DEquals,
+    DLess,
+    DMinus,
+    DNegate,
+    Dot,
+    DotR,
+    DPlus,
+    Drop,
+    DToS,
+    DTwoSlash,
+    DTwoStar,
+    Dup,
+    Emit,
+    Equals,
+    Execute,
+    Fetch,
+    Fill,
+    Find,
+    FmMod,
+    Forget,
+    Greater,
+    Here,
+    Hold,
+    I,
+    Immediate,
+    Invert,
+    J,
+    Key,
+    KeyQuestion,
+    Less,
+    LessNumberSign,
+    LShift,
+    Max,
+    Min,
+    Minus,
+    Mod,
+    Move,
+    MStar,
+    MStarSlash,
+    NumberSign,
+    NumberSignGreater,
+    NumberSignS,
+    Or,
+    Parse,
+    ParseCounted,
+    ParseName,
+    Plus,
+    PlusStore,
+    Refill,
+    Replaces,
+    ReturnStackPrint,
+    RightBracket,
+    RFetch,
+    RFrom,
+    Rot,
+    RShift,
+    Search,
+    SearchWordlist,
+    Sign,
+    Slash,
+    SlashMod,
+    SlashString,
+    SmRem,
+    Source,
+    Spaces,
+    StackPrint,
+    Star,
+    StarSlash,
+    StarSlashMod,
+    Store,
+    Substitute,
+    Swap,
+    Tick,
+    ToBody,
+    ToR,
+    TwoFetch,
+    TwoOver,
+    TwoRFetch,
+    TwoRFrom,
+    TwoSlash,
+    TwoStar,
+    TwoStore,
+    TwoSwap,
+    TwoToR,
+    Type,
+    UDot,
+    UDotR,
+    ULess,
+    UMSlashMod,
+    UMStar,
+    Unescape,
+    Unloop,
+    Within,
+    Xor,
+    #[cfg(feature = "float")]
+    DToF,
+    #[cfg(feature = "float")]
+    FDot,
+    #[cfg(feature = "float")]
+    FDrop,
+    #[cfg(feature = "float")]
+    FDup,
+    #[cfg(feature = "float")]
+    FExp,
+    #[cfg(feature = "float")]
+    FFetch,
+    #[cfg(feature = "float")]
+    FLess,
+    #[cfg(feature = "float")]
+    FLn,
+    #[cfg(feature = "float")]
+    FMinus,
+    #[cfg(feature = "float")]
+    FOver,
+    #[cfg(feature = "float")]
+    FPlus,
+    #[cfg(feature = "float")]
+    FSin,
+    #[cfg(feature = "float")]
+    FSlash,
+    #[cfg(feature = "float")]
+    FSqrt,
+    #[cfg(feature = "float")]
+    FStar,
+    #[cfg(feature = "float")]
+    FStore,
+    #[cfg(feature = "float")]
+    FSwap,
+    #[cfg(feature = "float")]
+    FToD,
+    #[cfg(feature = "float")]
+    FZeroEquals,
+}
+
+/// Parse a floating-point literal, which is distinguished from other numbers
+/// by its (possibly empty) exponent, as in `1.5e0` or `-2E`.
+#[cfg(feature = "float")]
+fn parse_float(word: &str) -> Option<Token> {
+    let (significand, exponent) = word.split_once(['e', 'E'])?;
+    let digits = significand.strip_prefix(['+', '-']).unwrap_or(significand);
+    if !digits.contains(|c: char| c.is_ascii_digit())
+        || !digits.chars().all(|c| c.is_ascii_digit() || c == '.')
+    {
+        return None;
+    }
+
+    let exponent = match exponent {
+        "" | "+" | "-" => "0",
+        _ => exponent,
+    };
+    format!("{significand}e{exponent}")
+        .parse()
+        .ok()
+        .map(Token::FNumber)
+}
+
+#[cfg(not(feature = "float"))]
+fn parse_float(_: &str) -> Option<Token> {
+    None
+}
+
+/// Parse an integer of `bits` bits, which may be written as either a signed
+/// or an unsigned number. Unless it is prefixed by `#` (decimal), `$`
+/// (hexadecimal) or `%` (binary), it is read in `radix`.
+fn parse_integer(word: &str, radix: Option<u32>, bits: u32) -> Option<i128> {
+    let (radix, word) = match word.split_at_checked(1) {
+        Some(("#", rest)) => (10, rest),
+        Some(("$", rest)) => (16, rest),
+        Some(("%", rest)) => (2, rest),
+        _ => (radix?, word),
+    };
+    let (negative, digits) = match word.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, word),
+    };
+    if digits.is_empty() || !digits.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+
+    let u = u128::from_str_radix(digits, radix).ok()?;
+    if negative {
+        (u <= 1 << (bits - 1)).then(|| (u as i128).wrapping_neg())
+    } else {
+        (u <= u128::MAX >> (u128::BITS - bits)).then_some(u as i128)
+    }
+}
+
+/// Parse a character literal, such as `'a'`.
+fn parse_char(word: &str) -> Option<char> {
+    let mut chars = word.strip_prefix('\'')?.strip_suffix('\'')?.chars();
+    chars.next().filter(|_| chars.next().is_none())
+}
+
+/// Write `n` spaces to `out`, a buffer at a time.
+fn write_spaces(out: &mut dyn Write, mut n: u64) -> io::Result<()> {
+    const SPACES: [u8; 64] = [b' '; 64];
+    while n > 0 {
+        let len = n.min(SPACES.len() as u64);
+        out.write_all(&SPACES[..len as usize])?;
+        n -= len;
+    }
+    Ok(())
+}
+
+/// Format `n` in `radix`, writing digits above nine as capital letters.
+fn format_integer(n: i128, radix: u32) -> String {
+    let mut digits = Vec::new();
+    let mut u = n.unsigned_abs();
+    loop {
+        digits.push(DIGITS[(u % u128::from(radix)) as usize]);
+        u /= u128::from(radix);
+        if u == 0 {
+            break;
+        }
+    }
+    if n < 0 {
+        digits.push(b'-');
+    }
+    digits.iter().rev().map(|&d| char::from(d)).collect()
+}
+
+/// Divide `n` by `d` (which must not be zero) following `division`, returning
+/// the remainder and quotient.
+fn divide(n: i128, d: i128, division: Division) -> (i128, i128) {
+    let (mut r, mut q) = (n.wrapping_rem(d), n.wrapping_div(d));
+    if let Division::Floored = division {
+        if r != 0 && (r < 0) != (d < 0) {
+            r += d;
+            q -= 1;
+        }
+    }
+    (r, q)
+}
+
+/// Compute `d * n / m` (where `m` is not zero) following `division`, through
+/// an intermediate product that may not fit in 128 bits. Returns the
+/// quotient, truncated to 128 bits, and whether it fit.
+fn scale(d: i128, n: i64, m: i64, division: Division) -> (i128, bool) {
+    let (d_abs, n_abs, m_abs) = (
+        d.unsigned_abs(),
+        u128::from(n.unsigned_abs()),
+        u128::from(m.unsigned_abs()),
+    );
+
+    // Divide before multiplying so every product is either the quotient or
+    // smaller than 2^126
+    let (mut q, high) = (d_abs / m_abs).overflowing_mul(n_abs);
+    let rest = d_abs % m_abs * n_abs;
+    let (sum, low) = q.overflowing_add(rest / m_abs);
+    q = sum;
+
+    let negative = (d < 0) != ((n < 0) != (m < 0));
+    let mut fits = !high && !low;
+    if let (true, Division::Floored) = (negative && rest % m_abs != 0, division) {
+        let (sum, carry) = q.overflowing_add(1);
+        q = sum;
+        fits &= !carry;
+    }
+
+    if negative {
+        (
+            q.wrapping_neg() as i128,
+            fits && q <= i128::MIN.unsigned_abs(),
+        )
+    } else {
+        (q as i128, fits && q <= i128::MAX as u128)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Evaluate `phrase`, panicking if it fails.
+    fn eval(machine: &mut Machine, phrase: &str) -> String {
+        machine
+            .eval(phrase)
+            .unwrap_or_else(|err| panic!("{phrase}: {err}"))
+    }
+
+    /// The message of the error from evaluating `phrase`.
+    fn error(machine: &mut Machine, phrase: &str) -> String {
+        match machine.eval(phrase) {
+            Ok(out) => panic!("{phrase}: expected an error, got {out:?}"),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn conditionals() {
+        let mut m = Machine::default();
+        eval(&mut m, ": choose if 1 else 2 then ;");
+        assert_eq!(eval(&mut m, "0 choose . 5 choose ."), "2 1 ");
+        eval(&mut m, ": nested if if 1 else 2 then else 3 then ;");
+        assert_eq!(
+            eval(&mut m, "1 1 nested . 0 1 nested . 1 0 nested ."),
+            "1 2 3 "
+        );
+        eval(&mut m, ": maybe if 7 . then ;");
+        assert_eq!(eval(&mut m, "0 maybe 1 maybe"), "7 ");
+        assert!(error(&mut m, ": bad then ;").contains("then"));
+        assert!(error(&mut m, ": bad if ;").contains("if"));
+    }
+
+    #[test]
+    fn counted_loops() {
+        let mut m = Machine::default();
+        eval(&mut m, ": up 3 0 do i . loop ;");
+        assert_eq!(eval(&mut m, "up"), "0 1 2 ");
+        eval(&mut m, ": none ?do i . loop ;");
+        assert_eq!(eval(&mut m, "0 0 none 2 1 none"), "1 ");
+        eval(&mut m, ": step do i . dup +loop drop ;");
+        assert_eq!(eval(&mut m, "3 10 0 step"), "0 3 6 9 ");
+        assert_eq!(eval(&mut m, "5 10 0 step"), "0 5 ");
+        assert_eq!(eval(&mut m, "-5 0 10 step"), "10 5 0 ");
+        eval(&mut m, ": grid 2 0 do 2 0 do j i + . loop loop ;");
+        assert_eq!(eval(&mut m, "grid"), "0 1 1 2 ");
+        eval(
+            &mut m,
+            ": early 10 0 do i . i 3 - if else leave then loop ;",
+        );
+        assert_eq!(eval(&mut m, "early"), "0 1 2 3 ");
+        eval(
+            &mut m,
+            ": quit 10 0 do i 2 = if unloop exit then i . loop 99 . ;",
+        );
+        assert_eq!(eval(&mut m, "quit"), "0 1 ");
+        assert!(error(&mut m, ": bad leave ;").contains("leave"));
+        assert!(error(&mut m, "i").starts_with("i:"));
+    }
+
+    #[test]
+    fn indefinite_loops() {
+        let mut m = Machine::default();
+        eval(&mut m, ": down begin dup while dup . 1 - repeat drop ;");
+        assert_eq!(eval(&mut m, "3 down 0 down"), "3 2 1 ");
+        eval(
+            &mut m,
+            ": tick begin dup . 1 - dup if 0 else 1 then until drop ;",
+        );
+        assert_eq!(eval(&mut m, "3 tick"), "3 2 1 ");
+        eval(&mut m, ": forever begin drop again ;");
+        assert!(error(&mut m, "1 2 3 forever").starts_with("drop:"));
+        eval(
+            &mut m,
+            ": until3 begin dup . 1+ dup 3 = if exit then again ;",
+        );
+        assert_eq!(eval(&mut m, "0 until3 ."), "0 1 2 3 ");
+        assert!(error(&mut m, ": bad repeat ;").contains("repeat"));
+    }
+
+    #[test]
+    fn return_stack() {
+        let mut m = Machine::default();
+        eval(&mut m, ": r 1 2 >r >r r@ r> r> ;");
+        assert_eq!(eval(&mut m, "r . . ."), "2 1 1 ");
+        eval(&mut m, ": r2 1 2 2>r 2r@ 2r> ;");
+        assert_eq!(eval(&mut m, "r2 . . . ."), "2 1 2 1 ");
+        eval(&mut m, ": show 3 >r .RS r> drop ;");
+        assert_eq!(eval(&mut m, "show"), "<1> 3 ");
+        assert!(error(&mut m, "r>").starts_with("r>:"));
+    }
+
+    #[test]
+    fn calls_by_reference() {
+        let mut m = Machine::default();
+        eval(&mut m, ": a 1 ;");
+        eval(&mut m, ": b a ;");
+        eval(&mut m, ": a 2 ;");
+        assert_eq!(eval(&mut m, "a . b ."), "2 1 ");
+        eval(&mut m, "forget a");
+        assert_eq!(eval(&mut m, "a . b ."), "1 1 ");
+
+        // Each definition calls the last, rather than copying it twice over
+        eval(&mut m, ": w0 1 ;");
+        for n in 1..64 {
+            eval(&mut m, &format!(": w{n} w{} w{} ;", n - 1, n - 1));
+        }
+
+        eval(&mut m, "marker m");
+        eval(&mut m, ": c 3 ;");
+        assert_eq!(eval(&mut m, "c ."), "3 ");
+        eval(&mut m, "m");
+        assert!(error(&mut m, "c").contains("'c'"));
+    }
+
+    #[test]
+    fn recursion() {
+        let mut m = Machine::default();
+        eval(&mut m, ": fact dup 1 - if dup 1 - recurse * then ;");
+        assert_eq!(eval(&mut m, "5 fact ."), "120 ");
+        eval(&mut m, ": deep recurse ;");
+        assert!(error(&mut m, "deep").contains("return stack overflow"));
+
+        // A word's own name refers to its previous definition by default
+        eval(&mut m, ": fact fact 1 + ;");
+        assert_eq!(eval(&mut m, "5 fact ."), "121 ");
+
+        // Words defined while compiling do not take the definition's place
+        eval(
+            &mut m,
+            ": cd dup if dup . 1 - recurse [ variable zz ] then ;",
+        );
+        assert_eq!(eval(&mut m, "3 cd ."), "3 2 1 0 ");
+
+        let mut m = Machine::new(Config {
+            recursive_names: true,
+            ..Config::default()
+        });
+        eval(&mut m, ": count dup . 1 - dup if count then ;");
+        assert_eq!(eval(&mut m, "3 count"), "3 2 1 ");
+        eval(&mut m, ": cd2 dup if dup . 1 - cd2 [ variable zz ] then ;");
+        assert_eq!(eval(&mut m, "3 cd2 ."), "3 2 1 0 ");
+    }
+
+    #[test]
+    fn variables_constants_and_values() {
+        let mut m = Machine::default();
+        eval(&mut m, "variable v 10 constant ten 7 value x");
+        eval(&mut m, "5 v ! 3 v +!");
+        assert_eq!(eval(&mut m, "v @ . ten . x ."), "8 10 7 ");
+        eval(&mut m, "9 to x");
+        assert_eq!(eval(&mut m, "x ."), "9 ");
+        eval(&mut m, ": set to x ;");
+        assert_eq!(eval(&mut m, "11 set x ."), "11 ");
+        eval(&mut m, "1 2 2constant pair 2variable d");
+        eval(&mut m, "3 d ! 4 d cell+ !");
+        assert_eq!(eval(&mut m, "pair . . d @ . d cell+ @ ."), "2 1 3 4 ");
+        assert!(error(&mut m, "1 to ten").contains("not a value"));
+        assert!(error(&mut m, "variable").contains("variable"));
+    }
+
+    #[test]
+    fn data_space() {
+        let mut m = Machine::default();
+        assert_eq!(eval(&mut m, "here 1 , 2 c, align here swap - ."), "8 ");
+        eval(&mut m, "here 1 , 2 , 3 c, constant t");
+        assert_eq!(eval(&mut m, "t @ . t cell+ @ . t 2 cells + c@ ."), "1 2 3 ");
+        assert_eq!(eval(&mut m, "here 100 allot -100 allot here - ."), "0 ");
+        assert_eq!(eval(&mut m, "3 aligned . 4 aligned ."), "4 4 ");
+        assert_eq!(
+            error(&mut m, "1000000 allot"),
+            "allot: data space exhausted"
+        );
+        assert!(error(&mut m, "here negate allot").contains("allot"));
+        assert!(error(&mut m, "-1 @").contains("-1"));
+        eval(&mut m, "here marker m variable q m");
+        assert_eq!(eval(&mut m, "here - ."), "0 ");
+        assert!(error(&mut m, "q").contains("'q'"));
+
+        let mut m = Machine::new(Config {
+            memory_size: 8,
+            ..Config::default()
+        });
+        eval(&mut m, "1 , 2 ,");
+        assert_eq!(error(&mut m, "3 ,"), ",: data space exhausted");
+        assert_eq!(error(&mut m, "3 c,"), "c,: data space exhausted");
+        assert_eq!(
+            error(&mut m, "variable v"),
+            "variable: data space exhausted"
+        );
+        assert_eq!(error(&mut m, "s\" abc\""), "s\": data space exhausted");
+    }
+
+    #[test]
+    fn create_does() {
+        let mut m = Machine::default();
+        eval(&mut m, "create t 1 , 2 ,");
+        assert_eq!(eval(&mut m, "t @ . t cell+ @ ."), "1 2 ");
+        eval(&mut m, ": const create , does> @ ;");
+        eval(&mut m, "42 const answer");
+        assert_eq!(eval(&mut m, "answer ."), "42 ");
+        eval(&mut m, ": array create cells allot does> swap cells + ;");
+        eval(&mut m, "3 array a");
+        eval(&mut m, "7 1 a ! 8 2 a !");
+        assert_eq!(eval(&mut m, "1 a @ . 2 a @ ."), "7 8 ");
+        assert!(error(&mut m, "create").contains("create"));
+
+        let mut m = Machine::default();
+        eval(&mut m, ": d does> 1 ;");
+        assert!(error(&mut m, "d").contains("does>"));
+    }
+
+    #[test]
+    fn comparisons() {
+        let mut m = Machine::default();
+        assert_eq!(
+            eval(&mut m, "1 2 < . 2 1 < . 1 1 = . 1 2 <> ."),
+            "-1 0 -1 -1 "
+        );
+        assert_eq!(
+            eval(&mut m, "-1 1 u< . 0 0= . 5 0> . -5 0< ."),
+            "0 -1 -1 -1 "
+        );
+        assert_eq!(
+            eval(&mut m, "true false or . 6 3 and . 5 invert ."),
+            "-1 2 -6 "
+        );
+        assert_eq!(
+            eval(&mut m, "3 1 5 within . 1 2 max . 1 2 min ."),
+            "-1 2 1 "
+        );
+        assert_eq!(
+            eval(&mut m, "-5 abs . 1 3 lshift . -8 2/ . 6 5 xor ."),
+            "5 8 -4 3 "
+        );
+        eval(
+            &mut m,
+            ": classify dup 0< if drop 1 else 0> if 2 else 3 then then ;",
+        );
+        assert_eq!(
+            eval(&mut m, "-5 classify . 0 classify . 5 classify ."),
+            "1 3 2 "
+        );
+    }
+
+    #[test]
+    fn arithmetic_errors() {
+        let mut m = Machine::default();
+        assert!(error(&mut m, "1 0 /").contains("division by zero"));
+        assert!(error(&mut m, "1 0 mod").contains("division by zero"));
+        assert!(error(&mut m, "1 0 /mod").contains("division by zero"));
+        assert_eq!(eval(&mut m, "2147483647 1 + ."), "-2147483648 ");
+        assert_eq!(eval(&mut m, "-2147483648 -1 / ."), "-2147483648 ");
+        assert_eq!(eval(&mut m, "1073741824 2* ."), "-2147483648 ");
+
+        let mut m = Machine::new(Config {
+            arithmetic: Arithmetic::Checked,
+            ..Config::default()
+        });
+        assert!(error(&mut m, "2147483647 1 +").contains("arithmetic overflow"));
+        assert!(error(&mut m, "65536 65536 *").contains("arithmetic overflow"));
+        assert!(error(&mut m, "-2147483648 -1 /").contains("arithmetic overflow"));
+        assert!(error(&mut m, "-2147483648 negate").contains("arithmetic overflow"));
+        assert!(error(&mut m, "1073741824 2*").contains("arithmetic overflow"));
+        assert_eq!(eval(&mut m, "6 7 * . -3 2* ."), "42 -6 ");
+    }
+
+    #[test]
+    fn division_modes() {
+        let mut m = Machine::default();
+        assert_eq!(eval(&mut m, "-7 2 / . -7 2 mod ."), "-3 -1 ");
+        assert_eq!(eval(&mut m, "-7 -1 2 fm/mod . ."), "-4 1 ");
+        assert_eq!(eval(&mut m, "-7 -1 2 sm/rem . ."), "-3 -1 ");
+        assert_eq!(eval(&mut m, "1000000 3000 2000 */ ."), "1500000 ");
+        assert_eq!(eval(&mut m, "7 3 5 */mod . ."), "4 1 ");
+        assert!(error(&mut m, "1 0 0 fm/mod").contains("division by zero"));
+
+        let mut m = Machine::new(Config {
+            division: Division::Floored,
+            ..Config::default()
+        });
+        assert_eq!(eval(&mut m, "-7 2 / . -7 2 mod ."), "-4 1 ");
+        assert_eq!(eval(&mut m, "-7 2 /mod . ."), "-4 1 ");
+        assert_eq!(eval(&mut m, "-7 1 2 */ ."), "-4 ");
+    }
+
+    #[test]
+    fn doubles() {
+        let mut m = Machine::default();
+        assert_eq!(eval(&mut m, "1. 2. d+ d. 5. 7. d- d."), "3 -2 ");
+        assert_eq!(eval(&mut m, "100000 100000 m* d."), "10000000000 ");
+        assert_eq!(eval(&mut m, "-1 2 um* d."), "8589934590 ");
+        assert_eq!(eval(&mut m, "10000000000. 100000 um/mod . ."), "100000 0 ");
+        assert_eq!(
+            eval(&mut m, "1. 2. d< . 2. 1. d< . -3 s>d dabs d."),
+            "-1 0 3 "
+        );
+        assert_eq!(eval(&mut m, "12345678901. d2* d."), "24691357802 ");
+        assert!(error(&mut m, "1. 0 um/mod").contains("division by zero"));
+    }
+
+    #[cfg(feature = "float")]
+    #[test]
+    fn floats() {
+        let mut m = Machine::default();
+        assert_eq!(eval(&mut m, "1.5e 2e f* f."), "3 ");
+        assert_eq!(
+            eval(&mut m, "1e 4e f/ f. 2e fsqrt 2e fsqrt f* 2e f- f0= ."),
+            "0.25 0 "
+        );
+        assert_eq!(eval(&mut m, "1e 2e f< . 1 2 ."), "-1 2 ");
+        assert!(error(&mut m, "f.").contains("f."));
+    }
+
+    #[test]
+    fn cell_widths() {
+        let mut m = Machine::new(Config {
+            cell_width: CellWidth::Bits16,
+            ..Config::default()
+        });
+        assert_eq!(
+            eval(&mut m, "32767 1+ . 1 cells . -1 1 u< ."),
+            "-32768 2 0 "
+        );
+        assert_eq!(eval(&mut m, "300 300 m* d."), "90000 ");
+        assert!(error(&mut m, "70000").contains("70000"));
+
+        // The data space extends past the largest signed cell
+        eval(&mut m, "20000 allot 20000 allot variable v 7 v ! 8 ,");
+        assert_eq!(eval(&mut m, "here u. here ."), "40266 -25270 ");
+        assert_eq!(eval(&mut m, "v @ . v cell+ @ . v 2 + c@ ."), "7 8 8 ");
+        assert_eq!(
+            eval(&mut m, "24245 allot here u. -1 here c! here c@ ."),
+            "64511 255 "
+        );
+        assert!(error(&mut m, "2 allot").contains("allot"));
+        assert_eq!(eval(&mut m, "source type"), "source type");
+        assert!(error(&mut m, "1 0 /").contains("division by zero"));
+
+        let mut m = Machine::new(Config {
+            cell_width: CellWidth::Bits64,
+            ..Config::default()
+        });
+        assert_eq!(eval(&mut m, "4294967296 dup * . 1 cells ."), "0 8 ");
+        assert_eq!(
+            eval(&mut m, "9223372036854775807 ."),
+            "9223372036854775807 "
+        );
+        assert_eq!(
+            eval(&mut m, "4294967296 dup m* d."),
+            "18446744073709551616 "
+        );
+    }
+
+    #[test]
+    fn bases() {
+        let mut m = Machine::default();
+        assert_eq!(eval(&mut m, "hex ff . decimal 255 ."), "FF 255 ");
+        assert_eq!(eval(&mut m, "$ff . #10 . %1010 . 'c' ."), "255 10 10 99 ");
+        assert_eq!(eval(&mut m, "binary 101 . decimal"), "101 ");
+        assert_eq!(eval(&mut m, "2 base ! 101 decimal ."), "5 ");
+        assert_eq!(eval(&mut m, "hex -1 . decimal"), "-1 ");
+        assert_eq!(eval(&mut m, "hex $10 #10 . . decimal"), "A 10 ");
+
+        // Words are found before numbers spelled with the same digits
+        assert_eq!(eval(&mut m, "hex 1. d. decimal"), "1 ");
+        eval(&mut m, ": add 1 + ;");
+        assert_eq!(eval(&mut m, "hex 5 add . decimal"), "6 ");
+    }
+
+    #[test]
+    fn pictured_output() {
+        let mut m = Machine::default();
+        assert_eq!(eval(&mut m, "123 s>d <# # # #s #> . c@ ."), "3 49 ");
+        assert_eq!(
+            eval(&mut m, "-42 dup abs s>d <# #s rot sign #> . c@ ."),
+            "3 45 "
+        );
+        assert_eq!(eval(&mut m, "5 s>d <# # '.' hold # #> . 1+ c@ ."), "3 46 ");
+        assert_eq!(eval(&mut m, "42 5 .r"), "   42");
+        assert_eq!(eval(&mut m, "-1 12 u.r"), "  4294967295");
+        assert_eq!(eval(&mut m, "-7. 4 d.r"), "  -7");
+        assert_eq!(eval(&mut m, "1 70000 .r").len(), 70000);
+        assert_eq!(eval(&mut m, "1. 70000 d.r").len(), 70000);
+        assert_eq!(eval(&mut m, "3 spaces 0 spaces -1 spaces"), "   ");
+        assert_eq!(eval(&mut m, "hex ff u. decimal"), "FF ");
+    }
+
+    #[test]
+    fn string_literals() {
+        let mut m = Machine::default();
+        eval(&mut m, r#": hi ." hello" ;"#);
+        assert_eq!(eval(&mut m, "hi"), "hello");
+        eval(&mut m, r#": s s" abc" ;"#);
+        assert_eq!(eval(&mut m, "s type s swap drop ."), "abc3 ");
+        eval(&mut m, r#": c c" xy" ;"#);
+        assert_eq!(eval(&mut m, "c count type"), "xy");
+        assert_eq!(eval(&mut m, r#"s" inline" type"#), "inline");
+        assert_eq!(eval(&mut m, r#"s\" a\tb\x41" type"#), "a\tbA");
+        assert_eq!(eval(&mut m, ".( now) 1 ."), "now1 ");
+        assert_eq!(eval(&mut m, "parse-name  word swap drop ."), "4 ");
+
+        // Interpreted strings leave the data space as it was
+        eval(&mut m, r#"create t 1 , s" ab" 2drop 2 ,"#);
+        assert_eq!(eval(&mut m, "t cell+ @ ."), "2 ");
+        assert!(error(&mut m, r#"s\" \k""#).contains("escape"));
+    }
+
+    #[test]
+    fn comments() {
+        let mut m = Machine::default();
+        eval(&mut m, ": x ( n -- n) dup ;");
+        assert_eq!(eval(&mut m, "3 x . ."), "3 3 ");
+        eval(&mut m, ": y ( a");
+        eval(&mut m, "b ) 5 ( c");
+        eval(&mut m, ") ;");
+        assert_eq!(eval(&mut m, "y ."), "5 ");
+        assert_eq!(eval(&mut m, "1 . ( unterminated"), "1 ");
+        assert_eq!(eval(&mut m, "2 ."), "2 ");
+        assert!(error(&mut m, ")").contains("comment"));
+    }
+
+    #[test]
+    fn string_words() {
+        let mut m = Machine::default();
+        assert_eq!(eval(&mut m, r#"s" abc" s" abd" compare ."#), "-1 ");
+        assert_eq!(eval(&mut m, r#"s" abc" s" abc" compare ."#), "0 ");
+        assert_eq!(eval(&mut m, r#"s" b" s" abc" compare ."#), "1 ");
+        assert_eq!(
+            eval(&mut m, r#"s" hello world" s" wor" search . type"#),
+            "-1 world"
+        );
+        assert_eq!(
+            eval(&mut m, r#"s" hello" s" xyz" search . type"#),
+            "0 hello"
+        );
+        assert_eq!(eval(&mut m, r#"s" hello" 2 /string type"#), "llo");
+        assert_eq!(eval(&mut m, r#"s" hi   " -trailing type"#), "hi");
+        eval(&mut m, "create buf 8 allot");
+        eval(&mut m, r#"buf 8 blank s" xyz" buf swap cmove"#);
+        assert_eq!(eval(&mut m, "buf 4 type"), "xyz ");
+        eval(&mut m, "buf buf 1+ 3 cmove>");
+        assert_eq!(eval(&mut m, "buf 4 type"), "xxyz");
+    }
+
+    #[test]
+    fn character_input() {
+        let mut m = Machine::default();
+        m.set_source(vec!["ab".to_string(), "line two".to_string()].into_iter());
+        assert_eq!(eval(&mut m, "key? . key . key? . key ."), "-1 97 -1 98 ");
+        assert_eq!(eval(&mut m, "key ."), "10 ");
+        eval(&mut m, "create buf 20 allot");
+        assert_eq!(eval(&mut m, "buf 20 accept buf swap type"), "line two");
+        assert_eq!(eval(&mut m, "key? ."), "0 ");
+        assert!(error(&mut m, "key").contains("key"));
+
+        m.set_source(vec!["1 2 +".to_string()].into_iter());
+        assert_eq!(eval(&mut m, "source type"), "source type");
+
+        // The line read by refill is interpreted in place of the rest of the
+        // current one
+        assert_eq!(eval(&mut m, "refill 99 ."), "");
+        assert_eq!(eval(&mut m, ". ."), "3 -1 ");
+        assert_eq!(eval(&mut m, "refill ."), "0 ");
+
+        // A menu waiting on scripted input sees it as soon as it is supplied
+        m.set_source(vec!["y".to_string()].into_iter());
+        eval(&mut m, ": menu begin key? until key ;");
+        assert_eq!(eval(&mut m, "menu emit"), "y ");
+    }
+
+    #[test]
+    fn streaming_output() {
+        let mut m = Machine::default();
+        let mut out = Vec::new();
+        m.eval_to("1 . 2 .", &mut out)
+            .unwrap_or_else(|err| panic!("{err}"));
+        assert!(m.eval_to("3 . bogus 4 .", &mut out).is_err());
+        assert_eq!(out, b"1 2 3 ");
+    }
+
+    #[test]
+    fn multiline_definitions() {
+        let mut m = Machine::default();
+        eval(&mut m, ": sq");
+        assert!(m.compiling());
+        eval(&mut m, "dup * [");
+        assert!(m.defining() && !m.compiling());
+        eval(&mut m, "2 3 + ] literal +");
+        assert!(m.compiling());
+        eval(&mut m, ";");
+        assert!(!m.defining());
+        assert_eq!(eval(&mut m, "3 sq ."), "14 ");
+
+        // An error abandons the unfinished definition
+        eval(&mut m, ": broken 1");
+        assert!(error(&mut m, "nosuchword").contains("nosuchword"));
+        assert!(!m.defining());
+        assert!(error(&mut m, "broken").contains("broken"));
+    }
+
+    #[test]
+    fn mixed_lines() {
+        let mut m = Machine::default();
+        assert_eq!(eval(&mut m, "1 2 + : sq dup * ; 3 sq . ."), "9 3 ");
+        eval(&mut m, ": foo 1 ; : foo 2 ;");
+        assert_eq!(eval(&mut m, "5 forget foo foo . ."), "1 5 ");
+        assert_eq!(eval(&mut m, "marker m : bar 1 ; bar . m"), "1 ");
+        assert!(error(&mut m, "bar").contains("bar"));
+        assert!(error(&mut m, "forget").contains("forget"));
+        eval(&mut m, ": end postpone ; ; immediate : one 1 end");
+        assert_eq!(eval(&mut m, "one ."), "1 ");
+        assert!(error(&mut m, ";").contains(";"));
+    }
+
+    #[test]
+    fn immediate_and_postpone() {
+        let mut m = Machine::default();
+        eval(&mut m, ": now 7 . ; immediate");
+        assert_eq!(eval(&mut m, ": later now ;"), "7 ");
+        assert_eq!(eval(&mut m, "later"), "");
+        eval(&mut m, ": my-if postpone if ; immediate");
+        eval(&mut m, ": t my-if 1 else 2 then . ;");
+        assert_eq!(eval(&mut m, "-1 t 0 t"), "1 2 ");
+        eval(
+            &mut m,
+            ": compile-dup postpone dup ; immediate : d compile-dup ;",
+        );
+        assert_eq!(eval(&mut m, "4 d . ."), "4 4 ");
+        eval(
+            &mut m,
+            ": my-var postpone variable ; immediate : w my-var ; w v",
+        );
+        assert_eq!(eval(&mut m, "3 v ! v @ ."), "3 ");
+        assert!(error(&mut m, ": bad postpone nothing ;").contains("nothing"));
+        assert_eq!(eval(&mut m, ": five [ 2 3 + ] literal ; five ."), "5 ");
+        assert_eq!(eval(&mut m, ": a [char] abc ; a ."), "97 ");
+    }
+
+    #[test]
+    fn execution_tokens() {
+        let mut m = Machine::default();
+        eval(&mut m, ": k 42 ; ' k constant xt-k");
+        assert_eq!(eval(&mut m, "xt-k execute ."), "42 ");
+        eval(&mut m, ": run ['] k execute ;");
+        assert_eq!(eval(&mut m, "run ."), "42 ");
+        eval(&mut m, ": twice dup compile, compile, ; immediate");
+        eval(&mut m, ": quad [ ' dup ] twice ;");
+        assert_eq!(eval(&mut m, "3 quad . . ."), "3 3 3 ");
+        eval(&mut m, "create foo 7 ,");
+        assert_eq!(eval(&mut m, "' foo >body @ ."), "7 ");
+        eval(&mut m, "' variable execute q 9 q !");
+        assert_eq!(eval(&mut m, "q @ . ' q >body q = ."), "9 -1 ");
+        assert!(error(&mut m, "' k >body").contains("not a created word"));
+        assert_eq!(
+            eval(
+                &mut m,
+                r#"c" k" find swap drop . c" nothing" find . count type"#
+            ),
+            "-1 0 nothing"
+        );
+        assert!(error(&mut m, "' nothing").contains("nothing"));
+        assert!(error(&mut m, "123456 execute").contains("execute"));
+    }
 }
@@ -14,18 +14,33 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use ignore_result::Ignore;
-use std::io::Write;
+use std::io::{self, Write};
+
+/// Read a line from stdin without its line terminator, locking stdin only for
+/// the one line so that programs can read from it too.
+fn read_line() -> Option<io::Result<String>> {
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) => None,
+        Ok(_) => {
+            let len = line.trim_end_matches(['\n', '\r']).len();
+            line.truncate(len);
+            Some(Ok(line))
+        }
+        Err(err) => Some(Err(err)),
+    }
+}
 
 fn main() {
-    let mut lines = std::io::stdin().lines();
-    let mut stdout = std::io::stdout().lock();
+    let mut stdout = io::stdout().lock();
     let mut machine = aforth::Machine::default();
+    machine.set_source(std::iter::from_fn(|| read_line()?.ok()));
 
     loop {
         stdout.write_all(b"> ").ignore();
         stdout.flush().ignore();
 
-        let phrase = match lines.next() {
+        let phrase = match read_line() {
             Some(Ok(line)) => line,
             Some(Err(err)) => {
                 eprintln!(":: error reading from stdin ({err})");
@@ -33,8 +48,9 @@ fn main() {
             }
             None => break,
         };
-        match machine.eval(&phrase) {
-            Ok(output) => println!("{output}ok"),
+        match machine.eval_to(&phrase, &mut stdout) {
+            Ok(()) if machine.defining() => stdout.write_all(b"compiled\n").ignore(),
+            Ok(()) => stdout.write_all(b"ok\n").ignore(),
             Err(err) => eprintln!(":: error evaluating: {err}"),
         }
     }